@@ -1,84 +1,1667 @@
 extern crate ansi_term;
 extern crate image;
 
+use std::borrow::Cow;
+use std::cmp::min;
+use std::collections::BTreeSet;
+use std::fmt;
 use std::io::{Write, self};
+use std::str::FromStr;
 
 use ansi_term::Colour::Fixed;
-use ansi_term::ANSIStrings;
-use image::{imageops, Pixel};
+use ansi_term::{ANSIStrings, Style};
+use image::{imageops, GenericImageView, Pixel};
 
 
-pub fn print_image(img: image::DynamicImage, true_colour: bool, width: u32, height: u32,filter: imageops::FilterType) {
-    let img = imageops::resize(&img, width, height, filter);
+/// Written by ansi_term at the end of each row.
+const RESET: &str = "\x1b[0m";
 
-    if !true_colour {
-        for y in 0..height {
-            //TODO: inc by 2 instead
-            if y%2 == 1 || y + 1 == height {
-                continue;
+/// How the pixels of the image are laid out in terminal cells, named as on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Two pixels per cell, stacked using the lower half block (▄).
+    HalfBlock,
+    /// One pixel per cell, drawn as a space on a coloured background. Blockier,
+    /// but doesn't depend on how the font renders block glyphs.
+    BackgroundOnly,
+    /// Four pixels per cell in a 2×2 grid, drawn with the quadrant block glyphs (▖▗▘▝…)
+    /// in the two colours that best split the four.
+    Quadrant,
+}
+
+impl RenderMode {
+    /// How many rows of pixels each row of cells shows.
+    pub fn pixels_per_row(self) -> u32 {
+        match self {
+            RenderMode::HalfBlock | RenderMode::Quadrant => 2,
+            RenderMode::BackgroundOnly => 1,
+        }
+    }
+
+    /// How many columns of pixels each column of cells shows.
+    pub fn pixels_per_column(self) -> u32 {
+        match self {
+            RenderMode::Quadrant => 2,
+            RenderMode::HalfBlock | RenderMode::BackgroundOnly => 1,
+        }
+    }
+}
+
+impl FromStr for RenderMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-block" => Ok(RenderMode::HalfBlock),
+            "bg-only" => Ok(RenderMode::BackgroundOnly),
+            "quadrant" => Ok(RenderMode::Quadrant),
+            _ => Err(format!("Unknown mode: {} (expected half-block, bg-only or quadrant)", s)),
+        }
+    }
+}
+
+impl fmt::Display for RenderMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            RenderMode::HalfBlock => "half-block",
+            RenderMode::BackgroundOnly => "bg-only",
+            RenderMode::Quadrant => "quadrant",
+        })
+    }
+}
+
+/// The filter used to resize images, named as on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Filter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    #[default]
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<Filter> for imageops::FilterType {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => imageops::Nearest,
+            Filter::Triangle => imageops::Triangle,
+            Filter::CatmullRom => imageops::CatmullRom,
+            Filter::Gaussian => imageops::Gaussian,
+            Filter::Lanczos3 => imageops::Lanczos3,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Filter::Nearest),
+            "triangle" => Ok(Filter::Triangle),
+            "catmullrom" => Ok(Filter::CatmullRom),
+            "gaussian" => Ok(Filter::Gaussian),
+            "lanczos3" => Ok(Filter::Lanczos3),
+            _ => Err(format!("Unknown filter: {} (expected nearest, triangle, catmullrom, gaussian or lanczos3)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Filter::Nearest => "nearest",
+            Filter::Triangle => "triangle",
+            Filter::CatmullRom => "catmullrom",
+            Filter::Gaussian => "gaussian",
+            Filter::Lanczos3 => "lanczos3",
+        })
+    }
+}
+
+/// A single channel of an image, named as on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    Alpha,
+    Luma,
+}
+
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "r" => Ok(Channel::Red),
+            "g" => Ok(Channel::Green),
+            "b" => Ok(Channel::Blue),
+            "a" => Ok(Channel::Alpha),
+            "luma" => Ok(Channel::Luma),
+            _ => Err(format!("Unknown channel: {} (expected r, g, b, a or luma)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Channel::Red => "r",
+            Channel::Green => "g",
+            Channel::Blue => "b",
+            Channel::Alpha => "a",
+            Channel::Luma => "luma",
+        })
+    }
+}
+
+/// How to work out the brightness of a colour, named as on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LumaFormula {
+    /// The weights of ITU-R BT.601, as used by standard-definition video and JPEG.
+    Rec601,
+    /// The weights of ITU-R BT.709, which match sRGB primaries.
+    #[default]
+    Rec709,
+    /// The plain mean of the three channels.
+    Average,
+}
+
+impl FromStr for LumaFormula {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rec601" => Ok(LumaFormula::Rec601),
+            "rec709" => Ok(LumaFormula::Rec709),
+            "average" => Ok(LumaFormula::Average),
+            _ => Err(format!("Unknown luma formula: {} (expected rec601, rec709 or average)", s)),
+        }
+    }
+}
+
+impl fmt::Display for LumaFormula {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            LumaFormula::Rec601 => "rec601",
+            LumaFormula::Rec709 => "rec709",
+            LumaFormula::Average => "average",
+        })
+    }
+}
+
+/// The brightness of an sRGB colour with `formula`, from 0 to 255. Like most software, this
+/// weights the gamma-encoded values rather than linear light.
+pub fn luma(rgb: [u8; 3], formula: LumaFormula) -> f32 {
+    let [r, g, b] = [rgb[0] as f32, rgb[1] as f32, rgb[2] as f32];
+    match formula {
+        LumaFormula::Rec601 => 0.299 * r + 0.587 * g + 0.114 * b,
+        LumaFormula::Rec709 => 0.2126 * r + 0.7152 * g + 0.0722 * b,
+        LumaFormula::Average => (r + g + b) / 3.0,
+    }
+}
+
+/// A greyscale copy of `img`, ignoring transparency.
+fn to_grey(img: &image::DynamicImage, formula: LumaFormula) -> image::GrayImage {
+    let rgba = img.to_rgba();
+    image::GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba[(x, y)];
+        image::Luma([luma([pixel[0], pixel[1], pixel[2]], formula).round() as u8])
+    })
+}
+
+/// An opaque greyscale image of one channel of `img`, with `Channel::Luma` worked out with
+/// `formula`. For `Channel::Alpha`, opaque pixels are white and transparent ones black.
+pub fn extract_channel(img: &image::DynamicImage, channel: Channel, formula: LumaFormula) -> image::DynamicImage {
+    let grey = match channel {
+        Channel::Luma => to_grey(img, formula),
+        _ => {
+            let index = match channel {
+                Channel::Red => 0,
+                Channel::Green => 1,
+                Channel::Blue => 2,
+                _ => 3,
+            };
+            let rgba = img.to_rgba();
+            image::GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| image::Luma([rgba[(x, y)][index]]))
+        }
+    };
+    image::DynamicImage::ImageLuma8(grey)
+}
+
+/// A colour vision deficiency to simulate, named as on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deficiency {
+    /// No long-wavelength (red) cones.
+    Protanopia,
+    /// No medium-wavelength (green) cones.
+    Deuteranopia,
+    /// No short-wavelength (blue) cones.
+    Tritanopia,
+}
+
+impl FromStr for Deficiency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "protanopia" => Ok(Deficiency::Protanopia),
+            "deuteranopia" => Ok(Deficiency::Deuteranopia),
+            "tritanopia" => Ok(Deficiency::Tritanopia),
+            _ => Err(format!("Unknown deficiency: {} (expected protanopia, deuteranopia or tritanopia)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Deficiency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Deficiency::Protanopia => "protanopia",
+            Deficiency::Deuteranopia => "deuteranopia",
+            Deficiency::Tritanopia => "tritanopia",
+        })
+    }
+}
+
+/// Linear RGB to LMS cone responses, from Viénot, Brettel and Mollon (1999).
+const RGB_TO_LMS: [[f32; 3]; 3] = [
+    [17.8824, 43.5161, 4.11935],
+    [3.45565, 27.1554, 3.86714],
+    [0.0299566, 0.184309, 1.46709],
+];
+
+/// The inverse of `RGB_TO_LMS`.
+const LMS_TO_RGB: [[f32; 3]; 3] = [
+    [0.080_944_45, -0.130_504_4, 0.116_721_07],
+    [-0.010_248_533, 0.054_019_33, -0.113_614_71],
+    [-0.000_365_296_94, -0.004_121_615, 0.693_511_4],
+];
+
+/// Rebuilds the response of the missing cone from the other two, so colours the
+/// deficiency confuses come out the same. White is left alone in every case; protanopia
+/// and deuteranopia also keep blue, and tritanopia keeps red.
+fn deficiency_matrix(deficiency: Deficiency) -> [[f32; 3]; 3] {
+    match deficiency {
+        Deficiency::Protanopia => [[0.0, 2.02344, -2.52581], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        Deficiency::Deuteranopia => [[1.0, 0.0, 0.0], [0.494207, 0.0, 1.24827], [0.0, 0.0, 1.0]],
+        Deficiency::Tritanopia => [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [-0.012245, 0.072035, 0.0]],
+    }
+}
+
+fn apply_matrix(matrix: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    let mut out = [0.0; 3];
+    for (out, row) in out.iter_mut().zip(matrix.iter()) {
+        *out = row[0] * v[0] + row[1] * v[1] + row[2] * v[2];
+    }
+    out
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+    (c * 255.0).round() as u8
+}
+
+/// `img` as it would look with `deficiency`, simulated in LMS space on linear RGB.
+pub fn simulate_deficiency(img: &image::DynamicImage, deficiency: Deficiency) -> image::DynamicImage {
+    let matrix = deficiency_matrix(deficiency);
+    let mut rgba = img.to_rgba();
+    for pixel in rgba.pixels_mut() {
+        let linear = [srgb_to_linear(pixel[0]), srgb_to_linear(pixel[1]), srgb_to_linear(pixel[2])];
+        let lms = apply_matrix(&matrix, apply_matrix(&RGB_TO_LMS, linear));
+        let rgb = apply_matrix(&LMS_TO_RGB, lms);
+        for channel in 0..3 {
+            pixel[channel] = linear_to_srgb(rgb[channel]);
+        }
+    }
+    image::DynamicImage::ImageRgba8(rgba)
+}
+
+/// A perceptual hash algorithm, named as on the command line. Similar images get hashes
+/// that differ in few bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashKind {
+    /// Each bit says whether a pixel of an 8x8 thumbnail is brighter than the mean.
+    Average,
+    /// Each bit says whether a pixel of a 9x8 thumbnail is brighter than the one to its left.
+    Difference,
+    /// Each bit says whether one of the 8x8 lowest frequencies of a 32x32 thumbnail is above
+    /// their median. Slower, but copes best with compression and colour changes.
+    Perceptual,
+}
+
+impl FromStr for HashKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ahash" => Ok(HashKind::Average),
+            "dhash" => Ok(HashKind::Difference),
+            "phash" => Ok(HashKind::Perceptual),
+            _ => Err(format!("Unknown hash: {} (expected ahash, dhash or phash)", s)),
+        }
+    }
+}
+
+impl fmt::Display for HashKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            HashKind::Average => "ahash",
+            HashKind::Difference => "dhash",
+            HashKind::Perceptual => "phash",
+        })
+    }
+}
+
+/// The 64-bit `kind` hash of `img`, with the first bit of the first row as the most
+/// significant. Brightness is worked out with `formula`, and transparency is ignored.
+pub fn image_hash(img: &image::DynamicImage, kind: HashKind, formula: LumaFormula) -> u64 {
+    let thumbnail = |width, height| {
+        let grey = to_grey(&img.resize_exact(width, height, imageops::FilterType::Triangle), formula);
+        grey.pixels().map(|pixel| pixel[0] as f64).collect::<Vec<_>>()
+    };
+    let bits: Vec<bool> = match kind {
+        HashKind::Average => {
+            let pixels = thumbnail(8, 8);
+            let mean = pixels.iter().sum::<f64>() / pixels.len() as f64;
+            pixels.iter().map(|&pixel| pixel > mean).collect()
+        }
+        HashKind::Difference => {
+            let pixels = thumbnail(9, 8);
+            pixels.chunks(9)
+                .flat_map(|row| row.windows(2).map(|pair| pair[1] > pair[0]))
+                .collect()
+        }
+        HashKind::Perceptual => {
+            let pixels = thumbnail(32, 32);
+            let frequencies = dct_low_frequencies(&pixels, 32, 8);
+            let mut sorted = frequencies.clone();
+            sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let median = (sorted[31] + sorted[32]) / 2.0;
+            frequencies.iter().map(|&frequency| frequency > median).collect()
+        }
+    };
+    bits.iter().fold(0, |hash, &bit| hash << 1 | bit as u64)
+}
+
+/// The `count`x`count` lowest frequencies of the 2D DCT-II of a `size`x`size` image, row by
+/// row.
+fn dct_low_frequencies(pixels: &[f64], size: usize, count: usize) -> Vec<f64> {
+    let basis: Vec<Vec<f64>> = (0..count).map(|k| {
+        (0..size).map(|n| {
+            (std::f64::consts::PI / size as f64 * (n as f64 + 0.5) * k as f64).cos()
+        }).collect()
+    }).collect();
+    let mut frequencies = Vec::with_capacity(count * count);
+    for v in &basis {
+        for u in &basis {
+            let mut sum = 0.0;
+            for (y, row) in pixels.chunks(size).enumerate() {
+                let row_sum: f64 = row.iter().zip(u).map(|(pixel, weight)| pixel * weight).sum();
+                sum += row_sum * v[y];
             }
+            frequencies.push(sum);
+        }
+    }
+    frequencies
+}
+
+/// A built-in image for checking how a terminal shows colour, named as on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestPattern {
+    /// The seven bars of a television test card, from white to blue.
+    ColourBars,
+    /// Every hue from left to right, fading to black from top to bottom.
+    Gradient,
+    /// Every grey level from black to white.
+    GreyRamp,
+    /// The 256-colour palette, sixteen colours to a row.
+    Palette,
+}
+
+impl FromStr for TestPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "colorbars" => Ok(TestPattern::ColourBars),
+            "gradient" => Ok(TestPattern::Gradient),
+            "grayscale-ramp" => Ok(TestPattern::GreyRamp),
+            "palette" => Ok(TestPattern::Palette),
+            _ => Err(format!("Unknown test pattern: {} (expected colorbars, gradient, grayscale-ramp or palette)", s)),
+        }
+    }
+}
+
+impl fmt::Display for TestPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            TestPattern::ColourBars => "colorbars",
+            TestPattern::Gradient => "gradient",
+            TestPattern::GreyRamp => "grayscale-ramp",
+            TestPattern::Palette => "palette",
+        })
+    }
+}
+
+/// Draws `pattern`.
+pub fn test_pattern(pattern: TestPattern) -> image::DynamicImage {
+    let img = match pattern {
+        TestPattern::ColourBars => {
+            const BARS: [[u8; 3]; 7] = [
+                [192, 192, 192], [192, 192, 0], [0, 192, 192], [0, 192, 0],
+                [192, 0, 192], [192, 0, 0], [0, 0, 192],
+            ];
+            image::RgbaImage::from_fn(7 * 32, 128, |x, _| {
+                let [r, g, b] = BARS[(x / 32) as usize];
+                image::Rgba([r, g, b, 255])
+            })
+        }
+        TestPattern::Gradient => image::RgbaImage::from_fn(360, 128, |x, y| {
+            let value = 1.0 - y as f32 / 127.0;
+            // The hue wheel, one degree per column.
+            let sector = x as f32 / 60.0;
+            let rising = sector.fract();
+            let (r, g, b) = match sector as u32 {
+                0 => (1.0, rising, 0.0),
+                1 => (1.0 - rising, 1.0, 0.0),
+                2 => (0.0, 1.0, rising),
+                3 => (0.0, 1.0 - rising, 1.0),
+                4 => (rising, 0.0, 1.0),
+                _ => (1.0, 0.0, 1.0 - rising),
+            };
+            let channel = |c: f32| (c * value * 255.0).round() as u8;
+            image::Rgba([channel(r), channel(g), channel(b), 255])
+        }),
+        TestPattern::GreyRamp => image::RgbaImage::from_fn(256, 64, |x, _| {
+            image::Rgba([x as u8, x as u8, x as u8, 255])
+        }),
+        TestPattern::Palette => image::RgbaImage::from_fn(16 * 8, 16 * 8, |x, y| {
+            let c = ANSI_COLOURS[(y / 8 * 16 + x / 8) as usize];
+            image::Rgba([c[0] as u8, c[1] as u8, c[2] as u8, 255])
+        }),
+    };
+    image::DynamicImage::ImageRgba8(img)
+}
 
-            let row: Vec<_> = (0..width).map(|x| {
-                let mut top = img[(x,y)];
-                let mut bottom = img[(x,y+1)];
-                blend_alpha(&mut top);
-                blend_alpha(&mut bottom);
-                let top_colour = find_colour_index(top.to_rgb().channels());
-                let bottom_colour = find_colour_index(bottom.to_rgb().channels());
-                Fixed(bottom_colour).on(Fixed(top_colour)).paint("▄")
-            }).collect();
-
-            print!("{}\n", ANSIStrings(&row));
-        }    
+/// Whether the terminal has a dark or a light background, named as on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Theme {
+    #[default]
+    Dark,
+    Light,
+}
+
+impl Theme {
+    /// The grey level transparent pixels are blended into, close to a typical background.
+    fn matte(self) -> u8 {
+        match self {
+            Theme::Dark => 38,
+            Theme::Light => 217,
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dark" => Ok(Theme::Dark),
+            "light" => Ok(Theme::Light),
+            _ => Err(format!("Unknown theme: {} (expected dark or light)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Theme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+        })
+    }
+}
+
+/// How 24-bit colour escapes separate their parameters, named as on the command line.
+/// Semicolons are the most widely understood; some terminals and multiplexers only handle
+/// the colon form from ITU T.416 properly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SgrSyntax {
+    #[default]
+    Semicolon,
+    Colon,
+}
+
+impl SgrSyntax {
+    /// Writes the escape that sets the background (`layer` 48) or foreground (38) to `rgb`.
+    fn write_rgb<W: Write>(self, out: &mut W, layer: u8, rgb: [u8; 3]) -> io::Result<()> {
+        match self {
+            SgrSyntax::Semicolon => write!(out, "\x1b[{};2;{};{};{}m", layer, rgb[0], rgb[1], rgb[2]),
+            // The empty parameter is the colour space, left as the default.
+            SgrSyntax::Colon => write!(out, "\x1b[{}:2::{}:{}:{}m", layer, rgb[0], rgb[1], rgb[2]),
+        }
+    }
+}
+
+impl FromStr for SgrSyntax {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "semicolon" => Ok(SgrSyntax::Semicolon),
+            "colon" => Ok(SgrSyntax::Colon),
+            _ => Err(format!("Unknown SGR syntax: {} (expected semicolon or colon)", s)),
+        }
+    }
+}
+
+impl fmt::Display for SgrSyntax {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SgrSyntax::Semicolon => "semicolon",
+            SgrSyntax::Colon => "colon",
+        })
+    }
+}
+
+/// How quadrant mode picks the two colours of each cell, named as on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellColours {
+    /// The darkest and brightest pixels, exactly. Keeps edges crisp, which suits text.
+    #[default]
+    TwoExtremes,
+    /// The average colours of the pixels darker and brighter than the cell's mean.
+    Average,
+    /// The average colours of the two halves of a median cut along the channel that varies
+    /// most. Follows gradients more closely.
+    MedianCut,
+}
+
+impl FromStr for CellColours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "two-extremes" => Ok(CellColours::TwoExtremes),
+            "average" => Ok(CellColours::Average),
+            "median-cut" => Ok(CellColours::MedianCut),
+            _ => Err(format!("Unknown cell colour method: {} (expected average, median-cut or two-extremes)", s)),
+        }
+    }
+}
+
+impl fmt::Display for CellColours {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            CellColours::TwoExtremes => "two-extremes",
+            CellColours::Average => "average",
+            CellColours::MedianCut => "median-cut",
+        })
+    }
+}
+
+/// A checkerboard drawn behind the transparent parts of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkerboard {
+    /// Width of each square in terminal cells. Squares are as tall as they are wide.
+    pub size: u32,
+    /// Grey levels of the two kinds of square.
+    pub shades: (u8, u8),
+}
+
+/// A rectangle of terminal cells, counted from the top left of the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// The part of this region that lies inside an image of `columns` by `rows` cells.
+    pub fn clip(&self, columns: u32, rows: u32) -> Region {
+        let x = self.x.min(columns);
+        let y = self.y.min(rows);
+        Region {
+            x,
+            y,
+            width: self.width.min(columns - x),
+            height: self.height.min(rows - y),
+        }
+    }
+}
+
+/// Settings for drawing an image.
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    /// Use 24-bit RGB escapes rather than the 256-colour palette.
+    pub true_colour: bool,
+    /// How to write 24-bit escapes.
+    pub sgr: SgrSyntax,
+    pub mode: RenderMode,
+    /// How quadrant mode picks the two colours of each cell.
+    pub cell_colours: CellColours,
+    pub filter: Filter,
+    /// Resize with a quick box filter instead of `filter`. Lower quality, but much faster
+    /// on large images.
+    pub fast: bool,
+    /// Sample each pixel bilinearly at its exact position in the original image instead of
+    /// resizing with `filter`. Steadier at awkward scales, but slower; overrides `fast`.
+    pub bilinear: bool,
+    /// Boost local contrast, resize with a sharp filter and sharpen again, to keep small text
+    /// legible. Best effort, meant for screenshots rather than photos; overrides `filter`.
+    pub text_optimize: bool,
+    /// Draw only this channel, in grey.
+    pub channel: Option<Channel>,
+    /// How to turn colours into grey, for `Channel::Luma`.
+    pub luma: LumaFormula,
+    /// Show the image as someone with this colour vision deficiency would see it.
+    pub simulate: Option<Deficiency>,
+    /// Add a transparent row to images with an odd number of rows of pixels in the
+    /// two-pixel-tall modes, rather than leaving the last row out.
+    pub pad_even: bool,
+    /// Restrict the output to these colours, printed as 24-bit escapes.
+    pub palette: Option<Vec<[u8; 3]>>,
+    /// Without a `palette`, restrict the output to at most this many colours picked for each
+    /// image, printed as 24-bit escapes.
+    pub max_colours: Option<usize>,
+    /// In half-block mode, average each pixel with its vertical neighbour outside the
+    /// cell, for smoother tone at the cost of vertical detail.
+    pub vblend: bool,
+    /// Composite transparent pixels over a checkerboard, rather than a grey that suits
+    /// `theme`.
+    pub checker: Option<Checkerboard>,
+    /// The terminal's background, which partly transparent pixels are blended into.
+    pub theme: Theme,
+    /// Reset the colours after the last row. Turn this off when embedding the output in
+    /// other coloured text, to carry on with the colours of the last cell.
+    pub reset: bool,
+    /// Draw only these cells, starting at the cursor, and move the cursor between rows
+    /// instead of printing newlines, to redraw part of an image already on screen. The
+    /// cursor is left after the last cell drawn.
+    pub region: Option<Region>,
+    /// Move the cursor over fully transparent cells instead of drawing them, to overlay the
+    /// image on whatever is already on screen.
+    pub sparse: bool,
+    /// Flush the output after every this many rows, so that a slow terminal shows the image
+    /// as it's drawn. With 0, `write_resized` only flushes at the end.
+    pub flush_rows: usize,
+    /// Start drawing this many columns right of and rows below the cursor. Ignored with a
+    /// `region`, which is always drawn at the cursor.
+    pub offset: (u32, u32),
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            true_colour: false,
+            sgr: SgrSyntax::default(),
+            mode: RenderMode::HalfBlock,
+            cell_colours: CellColours::default(),
+            filter: Filter::default(),
+            fast: false,
+            bilinear: false,
+            text_optimize: false,
+            channel: None,
+            luma: LumaFormula::default(),
+            simulate: None,
+            pad_even: false,
+            palette: None,
+            max_colours: None,
+            vblend: false,
+            checker: None,
+            theme: Theme::default(),
+            reset: true,
+            region: None,
+            sparse: false,
+            flush_rows: 0,
+            offset: (0, 0),
+        }
+    }
+}
+
+impl RenderOptions {
+    fn uses_rgb_escapes(&self) -> bool {
+        self.true_colour || self.palette.is_some() || self.max_colours.is_some()
+    }
+
+    /// The colour to print for `pixel` when using 24-bit escapes.
+    fn rgb(&self, mut pixel: image::Rgba<u8>) -> [u8; 3] {
+        blend_alpha(&mut pixel, self.theme.matte());
+        let rgb = [pixel[0], pixel[1], pixel[2]];
+        match self.palette {
+            Some(ref palette) => palette[nearest_colour(&rgb, palette)],
+            None => rgb,
+        }
+    }
+}
+
+/// Prints `img` to stdout. `height` is in pixel rows, i.e. `options.mode.pixels_per_row()`
+/// per terminal row.
+pub fn print_image(img: image::DynamicImage, options: &RenderOptions, width: u32, height: u32) {
+    let img = resize_image(&img, options, width, height);
+    let stdout = io::stdout();
+    write_resized(&mut stdout.lock(), &img, options).unwrap();
+}
+
+/// Renders `img` as it would be printed by `print_image`, returning the bytes rather than
+/// writing them, e.g. to send them with a single `write_all`.
+pub fn render_bytes(img: &image::DynamicImage, options: &RenderOptions, width: u32, height: u32) -> Vec<u8> {
+    let img = resize_image(img, options, width, height);
+    let mut out = Vec::new();
+    write_resized(&mut out, &img, options).expect("writing to a Vec can't fail");
+    out
+}
+
+/// Renders each of `images` like `render_bytes`, all at the same size, e.g. the frames of an
+/// animation. Calls `on_progress` with the number done so far and the total after each one,
+/// for showing a progress bar.
+pub fn render_many<F: FnMut(usize, usize)>(images: &[image::DynamicImage], options: &RenderOptions, width: u32, height: u32, mut on_progress: F) -> Vec<Vec<u8>> {
+    let mut renderer = Renderer::new(options.clone());
+    images.iter().enumerate().map(|(i, img)| {
+        let rendered = renderer.render(img, width, height).as_bytes().to_vec();
+        on_progress(i + 1, images.len());
+        rendered
+    }).collect()
+}
+
+/// The largest part of `img` with an aspect ratio of `width`:`height`, taken from its centre.
+pub fn crop_to_aspect(img: &image::DynamicImage, width: u32, height: u32) -> image::DynamicImage {
+    let (src_width, src_height) = img.dimensions();
+    let (crop_width, crop_height) = if src_width as u64 * height as u64 > src_height as u64 * width as u64 {
+        ((src_height as u64 * width as u64 / height as u64).max(1) as u32, src_height)
     } else {
-        let mut row = Vec::new();
-        for y in 0..height {
-            //TODO: inc by 2 instead
-            if y%2 == 1 || y + 1 == height {
+        (src_width, (src_width as u64 * height as u64 / width as u64).max(1) as u32)
+    };
+    img.crop_imm((src_width - crop_width) / 2, (src_height - crop_height) / 2, crop_width, crop_height)
+}
+
+/// Resizes `img` to the pixel grid `options.mode` draws, for an output of `width` columns
+/// and `height` pixel rows.
+pub fn resize_image(img: &image::DynamicImage, options: &RenderOptions, width: u32, height: u32) -> image::RgbaImage {
+    let (width, height) = scaled_dimensions(options, width, height);
+    let resized = scale_image(img, options, width, height);
+    if needs_padding(options, height) {
+        // The extra row is transparent, so it's drawn like any other transparent area.
+        let mut padded = image::RgbaImage::new(width, height + 1);
+        imageops::replace(&mut padded, &resized, 0, 0);
+        padded
+    } else {
+        resized
+    }
+}
+
+fn needs_padding(options: &RenderOptions, height: u32) -> bool {
+    options.pad_even && !height.is_multiple_of(options.mode.pixels_per_row())
+}
+
+fn scale_image(img: &image::DynamicImage, options: &RenderOptions, width: u32, height: u32) -> image::RgbaImage {
+    let simulated;
+    let img = match options.simulate {
+        Some(deficiency) => {
+            simulated = simulate_deficiency(img, deficiency);
+            &simulated
+        }
+        None => img,
+    };
+    let extracted;
+    let img = match options.channel {
+        Some(channel) => {
+            extracted = extract_channel(img, channel, options.luma);
+            &extracted
+        }
+        None => img,
+    };
+    if img.dimensions() == (width, height) {
+        // Already the right size, so any filter would only blur it.
+        return img.to_rgba();
+    }
+    if options.bilinear {
+        sample_bilinear(&img.to_rgba(), width, height)
+    } else if options.text_optimize {
+        let contrasted = img.unsharpen(2.0, 8);
+        let resized = imageops::resize(&contrasted, width, height, imageops::Lanczos3);
+        imageops::unsharpen(&resized, 0.5, 4)
+    } else if options.fast {
+        imageops::thumbnail(img, width, height)
+    } else {
+        imageops::resize(img, width, height, options.filter.into())
+    }
+}
+
+/// Builds a `width`×`height` image by interpolating `img` at the centre of each output
+/// pixel, mapped back to the original's coordinates.
+fn sample_bilinear(img: &image::RgbaImage, width: u32, height: u32) -> image::RgbaImage {
+    let (src_width, src_height) = img.dimensions();
+    if src_width == 0 || src_height == 0 {
+        return image::RgbaImage::new(width, height);
+    }
+    let scale_x = src_width as f32 / width as f32;
+    let scale_y = src_height as f32 / height as f32;
+    let max_x = (src_width - 1) as f32;
+    let max_y = (src_height - 1) as f32;
+
+    image::RgbaImage::from_fn(width, height, |x, y| {
+        let sx = ((x as f32 + 0.5) * scale_x - 0.5).max(0.0).min(max_x);
+        let sy = ((y as f32 + 0.5) * scale_y - 0.5).max(0.0).min(max_y);
+        let (x0, y0) = (sx as u32, sy as u32);
+        let (x1, y1) = (min(x0 + 1, src_width - 1), min(y0 + 1, src_height - 1));
+        let (fx, fy) = (sx - x0 as f32, sy - y0 as f32);
+
+        let mut pixel = [0; 4];
+        for (c, value) in pixel.iter_mut().enumerate() {
+            let top = img[(x0, y0)][c] as f32 * (1.0 - fx) + img[(x1, y0)][c] as f32 * fx;
+            let bottom = img[(x0, y1)][c] as f32 * (1.0 - fx) + img[(x1, y1)][c] as f32 * fx;
+            *value = (top * (1.0 - fy) + bottom * fy).round() as u8;
+        }
+        image::Rgba(pixel)
+    })
+}
+
+/// The size `resize_image` will produce for the given output size.
+pub fn resized_dimensions(options: &RenderOptions, width: u32, height: u32) -> (u32, u32) {
+    let (width, height) = scaled_dimensions(options, width, height);
+    if needs_padding(options, height) {
+        (width, height + 1)
+    } else {
+        (width, height)
+    }
+}
+
+/// The size `resize_image` scales the image to, before any padding. Images that are
+/// already this size are used as they are, without resampling.
+pub fn scaled_dimensions(options: &RenderOptions, width: u32, height: u32) -> (u32, u32) {
+    (width * options.mode.pixels_per_column(), height)
+}
+
+/// Writes an image already sized by `resize_image` to `out`.
+pub fn write_resized<W: Write + ?Sized>(out: &mut W, img: &image::RgbaImage, options: &RenderOptions) -> io::Result<()> {
+    write_cells(out, img, options, &mut ColourCache::default())?;
+    out.flush()
+}
+
+/// Writes `img` over `previous`, both already sized by `resize_image` and drawn from the same
+/// place, redrawing only the cells that look different, e.g. for the next frame of an
+/// animation. The cursor ends up where `write_resized` would leave it. With a region, or
+/// images of different sizes, the whole of `img` is drawn.
+pub fn write_changes<W: Write + ?Sized>(out: &mut W, previous: &image::RgbaImage, img: &image::RgbaImage, options: &RenderOptions) -> io::Result<()> {
+    write_changed_cells(out, previous, img, options, &mut ColourCache::default())?;
+    out.flush()
+}
+
+/// Draws images with the same options one after another, keeping the 256-colour lookups and
+/// the output buffer from one image to the next. Worth it for galleries and previewers
+/// that draw many images.
+pub struct Renderer {
+    options: RenderOptions,
+    cache: ColourCache,
+    buffer: Vec<u8>,
+}
+
+impl Renderer {
+    pub fn new(options: RenderOptions) -> Self {
+        Renderer { options, cache: ColourCache::new(), buffer: Vec::new() }
+    }
+
+    pub fn options(&self) -> &RenderOptions {
+        &self.options
+    }
+
+    /// Renders `img` like `render_bytes`. The output is only kept until the next call.
+    pub fn render(&mut self, img: &image::DynamicImage, width: u32, height: u32) -> &str {
+        let img = resize_image(img, &self.options, width, height);
+        self.render_resized(&img)
+    }
+
+    /// Renders an image already sized by `resize_image`, like `write_resized`.
+    pub fn render_resized(&mut self, img: &image::RgbaImage) -> &str {
+        self.buffer.clear();
+        write_cells(&mut self.buffer, img, &self.options, &mut self.cache).expect("writing to a Vec can't fail");
+        std::str::from_utf8(&self.buffer).expect("escapes and glyphs are UTF-8")
+    }
+
+    /// Renders the changes from `previous` to `img`, like `write_changes`.
+    pub fn render_changes(&mut self, previous: &image::RgbaImage, img: &image::RgbaImage) -> &str {
+        self.buffer.clear();
+        write_changed_cells(&mut self.buffer, previous, img, &self.options, &mut self.cache).expect("writing to a Vec can't fail");
+        std::str::from_utf8(&self.buffer).expect("escapes and glyphs are UTF-8")
+    }
+}
+
+/// The 256-colour indices already picked for recently seen colours, after blending, since
+/// each one means searching the whole palette. Each slot holds a colour above the index
+/// picked for it, and colours that hash to the same slot replace each other. Empty when not
+/// caching; 0 marks slots not used yet, as indices below 16 are never picked.
+#[derive(Default)]
+struct ColourCache(Vec<u32>);
+
+/// log2 of the number of slots in a `ColourCache`. 256 KiB stays in cache and costs little
+/// to fill, even when almost every colour is new.
+const COLOUR_CACHE_BITS: u32 = 16;
+
+impl ColourCache {
+    fn new() -> Self {
+        ColourCache(vec![0; 1 << COLOUR_CACHE_BITS])
+    }
+
+    fn index(&mut self, mut pixel: image::Rgba<u8>, matte: u8) -> u8 {
+        blend_alpha(&mut pixel, matte);
+        if self.0.is_empty() {
+            return find_colour_index(pixel.to_rgb().channels());
+        }
+        let rgb = (pixel[0] as u32) << 16 | (pixel[1] as u32) << 8 | pixel[2] as u32;
+        // Fibonacci hashing, so that similar colours spread over the table.
+        let slot = &mut self.0[(rgb.wrapping_mul(0x9e37_79b9) >> (32 - COLOUR_CACHE_BITS)) as usize];
+        if *slot >> 8 != rgb || *slot == 0 {
+            *slot = rgb << 8 | find_colour_index(pixel.to_rgb().channels()) as u32;
+        }
+        *slot as u8
+    }
+}
+
+fn write_cells<W: Write + ?Sized>(out: &mut W, img: &image::RgbaImage, options: &RenderOptions, cache: &mut ColourCache) -> io::Result<()> {
+    let rows = cell_rows(img, options);
+    let options = &*quantize(&rows, options);
+    let region = match options.region {
+        Some(region) => region,
+        None => {
+            let (x_offset, y_offset) = options.offset;
+            for _ in 0..y_offset {
+                out.write_all(b"\n")?;
+            }
+            for (row, cells) in rows.iter().enumerate() {
+                if x_offset > 0 {
+                    write!(out, "\x1b[{}C", x_offset)?;
+                }
+                write_row(out, cells, options, cache, row + 1 == rows.len())?;
+                out.write_all(b"\n")?;
+                flush_row(out, options, row)?;
+            }
+            return Ok(());
+        }
+    };
+
+    let columns = rows.first().map_or(0, |cells| cells.len() as u32);
+    let region = region.clip(columns, rows.len() as u32);
+    if region.width == 0 {
+        return Ok(());
+    }
+    let (start, end) = (region.x as usize, (region.x + region.width) as usize);
+    let rows = &rows[region.y as usize..(region.y + region.height) as usize];
+    for (row, cells) in rows.iter().enumerate() {
+        let last = row + 1 == rows.len();
+        write_row(out, &cells[start..end], options, cache, last)?;
+        if !last {
+            // Back to the first column of the region, one row down.
+            write!(out, "\x1b[{}D\x1b[B", region.width)?;
+        }
+        flush_row(out, options, row)?;
+    }
+    Ok(())
+}
+
+/// Writes the cells of `img` that are printed differently from those of `previous`, moving
+/// the cursor over the rest, in the same layout as `write_cells` without a region.
+fn write_changed_cells<W: Write + ?Sized>(out: &mut W, previous: &image::RgbaImage, img: &image::RgbaImage, options: &RenderOptions, cache: &mut ColourCache) -> io::Result<()> {
+    if options.region.is_some() || previous.dimensions() != img.dimensions() {
+        return write_cells(out, img, options, cache);
+    }
+    let old_rows = cell_rows(previous, options);
+    let old_options = &*quantize(&old_rows, options);
+    let rows = cell_rows(img, options);
+    let options = &*quantize(&rows, options);
+
+    // Comparing the bytes printed for each cell catches every change that shows, including
+    // to the palette picked for --max-colors, and ignores those that don't.
+    let mut printed = |cell: &Cell, options: &RenderOptions| {
+        let mut bytes = Vec::new();
+        write_run(&mut bytes, std::slice::from_ref(cell), options, cache, true).expect("writing to a Vec can't fail");
+        bytes
+    };
+    let changed: Vec<Vec<bool>> = rows.iter().zip(&old_rows).map(|(cells, old_cells)| {
+        cells.iter().zip(old_cells).map(|(cell, old_cell)| {
+            // Sparse output never draws transparent cells, so they can't show a change.
+            !(options.sparse && cell.is_transparent()) && printed(cell, options) != printed(old_cell, old_options)
+        }).collect()
+    }).collect();
+    let last_changed = changed.iter().enumerate().rev()
+        .find_map(|(row, changed)| changed.iter().rposition(|&changed| changed).map(|column| (row, column)));
+
+    let (x_offset, y_offset) = options.offset;
+    for _ in 0..y_offset {
+        out.write_all(b"\n")?;
+    }
+    for (row, (cells, changed)) in rows.iter().zip(&changed).enumerate() {
+        let mut skip = x_offset as usize;
+        let mut column = 0;
+        while column < cells.len() {
+            if !changed[column] {
+                skip += 1;
+                column += 1;
+                continue;
+            }
+            let end = column + changed[column..].iter().take_while(|&&changed| changed).count();
+            if skip > 0 {
+                write!(out, "\x1b[{}C", skip)?;
+                skip = 0;
+            }
+            // As with sparse output, only the last run may leave its colours set.
+            let reset = options.reset || last_changed != Some((row, end - 1));
+            write_run(out, &cells[column..end], options, cache, reset)?;
+            column = end;
+        }
+        out.write_all(b"\n")?;
+        flush_row(out, options, row)?;
+    }
+    Ok(())
+}
+
+/// Flushes `out` after the 0-based `row` if it ends a batch of `options.flush_rows`.
+fn flush_row<W: Write + ?Sized>(out: &mut W, options: &RenderOptions, row: usize) -> io::Result<()> {
+    if options.flush_rows > 0 && (row + 1).is_multiple_of(options.flush_rows) {
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// A colour printed by `write_resized`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UsedColour {
+    /// The index in the 256-colour palette, unless 24-bit escapes are used.
+    pub index: Option<u8>,
+    pub rgb: [u8; 3],
+}
+
+/// The distinct colours `write_resized` prints for an image already sized by `resize_image`,
+/// in palette order.
+pub fn colours_used(img: &image::RgbaImage, options: &RenderOptions) -> Vec<UsedColour> {
+    let rows = cell_rows(img, options);
+    let options = &*quantize(&rows, options);
+    let mut colours = BTreeSet::new();
+    for cell in rows.iter().flatten() {
+        for &pixel in cell.fg.iter().chain(Some(&cell.bg)) {
+            colours.insert(if options.uses_rgb_escapes() {
+                UsedColour { rgb: options.rgb(pixel), index: None }
+            } else if pixel[3] == 0 {
+                // Left in the terminal's own background.
                 continue;
+            } else {
+                let index = ansi_index(pixel, options.theme.matte());
+                let c = &ANSI_COLOURS[index as usize];
+                UsedColour { rgb: [c[0] as u8, c[1] as u8, c[2] as u8], index: Some(index) }
+            });
+        }
+    }
+    colours.into_iter().collect()
+}
+
+/// `options` with a palette of at most `options.max_colours` colours picked for these cells,
+/// if there's a limit.
+fn quantize<'a>(rows: &[Vec<Cell>], options: &'a RenderOptions) -> Cow<'a, RenderOptions> {
+    let max_colours = match options.max_colours {
+        Some(max_colours) if options.palette.is_none() => max_colours,
+        _ => return Cow::Borrowed(options),
+    };
+    let colours: Vec<_> = rows.iter().flatten()
+        .flat_map(|cell| cell.fg.into_iter().chain(Some(cell.bg)))
+        .map(|pixel| options.rgb(pixel))
+        .collect();
+    Cow::Owned(RenderOptions { palette: Some(median_cut(colours, max_colours)), ..options.clone() })
+}
+
+/// Picks up to `max_colours` colours representing `colours`, by repeatedly splitting the
+/// group with the widest range of any channel at its median.
+fn median_cut(colours: Vec<[u8; 3]>, max_colours: usize) -> Vec<[u8; 3]> {
+    // Each group with its widest channel and that channel's range.
+    let measure = |group: Vec<[u8; 3]>| {
+        let (range, channel) = (0..3).map(|channel| {
+            let values = group.iter().map(|colour| colour[channel]);
+            (values.clone().max().unwrap_or(0) - values.min().unwrap_or(0), channel)
+        }).max().unwrap();
+        (group, channel, range)
+    };
+
+    let mut groups = vec![measure(colours)];
+    while groups.len() < max_colours {
+        let (index, _) = groups.iter().enumerate().max_by_key(|&(_, &(_, _, range))| range).unwrap();
+        if groups[index].2 == 0 {
+            // Every group is a single colour already.
+            break;
+        }
+        let (mut group, channel, _) = groups.swap_remove(index);
+        group.sort_by_key(|colour| colour[channel]);
+        let upper = group.split_off(group.len() / 2);
+        groups.push(measure(group));
+        groups.push(measure(upper));
+    }
+
+    groups.iter().filter(|&(group, _, _)| !group.is_empty()).map(|(group, _, _)| {
+        let mut sum = [0u64; 3];
+        for colour in group {
+            for (total, &channel) in sum.iter_mut().zip(colour.iter()) {
+                *total += channel as u64;
+            }
+        }
+        let count = group.len() as u64;
+        [(sum[0] / count) as u8, (sum[1] / count) as u8, (sum[2] / count) as u8]
+    }).collect()
+}
+
+/// Splits an image already sized by `resize_image` into rows of terminal cells.
+fn cell_rows(img: &image::RgbaImage, options: &RenderOptions) -> Vec<Vec<Cell>> {
+    let composited;
+    let img = match options.checker {
+        Some(checker) => {
+            composited = composite_checkerboard(img, checker, options.mode);
+            &composited
+        }
+        None => img,
+    };
+    let (width, height) = img.dimensions();
+    let rows = height / options.mode.pixels_per_row();
+
+    (0..rows).map(|row| match options.mode {
+        RenderMode::HalfBlock => (0..width).map(|x| {
+            let (top, bottom) = cell_pixels(img, x, row * 2, options.vblend);
+            Cell { glyph: "▄", fg: Some(bottom), bg: top }
+        }).collect(),
+        RenderMode::BackgroundOnly => (0..width).map(|x| {
+            Cell { glyph: " ", fg: None, bg: img[(x, row)] }
+        }).collect(),
+        RenderMode::Quadrant => (0..width / 2).map(|x| {
+            let (x, y) = (x * 2, row * 2);
+            let pixels = [img[(x, y)], img[(x + 1, y)], img[(x, y + 1)], img[(x + 1, y + 1)]];
+            quadrant_cell(pixels, options.theme.matte(), options.cell_colours, options.luma)
+        }).collect(),
+    }).collect()
+}
+
+fn composite_checkerboard(img: &image::RgbaImage, checker: Checkerboard, mode: RenderMode) -> image::RgbaImage {
+    // Square sizes in pixels. A cell is one unit wide and two tall.
+    let (square_width, square_height) = match mode {
+        RenderMode::HalfBlock => (checker.size, checker.size),
+        RenderMode::BackgroundOnly => (checker.size, checker.size / 2),
+        RenderMode::Quadrant => (checker.size * 2, checker.size),
+    };
+    let (square_width, square_height) = (square_width.max(1), square_height.max(1));
+
+    let mut img = img.clone();
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let shade = if (x / square_width + y / square_height) % 2 == 0 {
+            checker.shades.0
+        } else {
+            checker.shades.1
+        };
+        let alpha = pixel[3] as f32 / 255.0;
+        for channel in 0..3 {
+            pixel[channel] = (alpha * pixel[channel] as f32 + (1.0 - alpha) * shade as f32) as u8;
+        }
+        pixel[3] = 255;
+    }
+    img
+}
+
+/// A terminal cell: `glyph` drawn in `fg` on `bg`.
+struct Cell {
+    glyph: &'static str,
+    fg: Option<image::Rgba<u8>>,
+    bg: image::Rgba<u8>,
+}
+
+impl Cell {
+    fn is_transparent(&self) -> bool {
+        self.bg[3] == 0 && self.fg.is_none_or(|fg| fg[3] == 0)
+    }
+}
+
+fn write_row<W: Write + ?Sized>(out: &mut W, cells: &[Cell], options: &RenderOptions, cache: &mut ColourCache, last: bool) -> io::Result<()> {
+    let reset = options.reset || !last;
+    if !options.sparse {
+        return write_run(out, cells, options, cache, reset);
+    }
+
+    let mut rest = cells;
+    while !rest.is_empty() {
+        let skip = rest.iter().take_while(|cell| cell.is_transparent()).count();
+        // Regions go back to their first column by the width, so need the cursor at the end.
+        if skip == rest.len() && options.region.is_none() {
+            break;
+        }
+        if skip > 0 {
+            write!(out, "\x1b[{}C", skip)?;
+        }
+        rest = &rest[skip..];
+
+        let drawn = rest.iter().take_while(|cell| !cell.is_transparent()).count();
+        let (run, after) = rest.split_at(drawn);
+        // Skipped cells keep the colours set before them, so only the last run may leave
+        // its colours set.
+        let final_run = after.iter().all(Cell::is_transparent);
+        if !run.is_empty() {
+            write_run(out, run, options, cache, reset || !final_run)?;
+        }
+        rest = after;
+    }
+    Ok(())
+}
+
+/// Writes `cells` one after another, resetting the colours afterwards if `reset`.
+fn write_run<W: Write + ?Sized>(out: &mut W, cells: &[Cell], options: &RenderOptions, cache: &mut ColourCache, reset: bool) -> io::Result<()> {
+    if options.uses_rgb_escapes() {
+        let mut row = Vec::new();
+        for cell in cells {
+            let bg = options.rgb(cell.bg);
+            options.sgr.write_rgb(&mut row, 48, bg)?;
+            if let Some(fg) = cell.fg {
+                options.sgr.write_rgb(&mut row, 38, options.rgb(fg))?;
             }
+            row.write_all(cell.glyph.as_bytes())?;
+        }
+        if reset {
+            write!(row, "\x1b[m")?;
+        }
+        out.write_all(&row)
+    } else {
+        let strings: Vec<_> = cells.iter().map(|cell| {
+            let (style, glyph) = ansi_style(cell, cache, options.theme.matte());
+            style.paint(glyph)
+        }).collect();
 
-            for x in 0..width {
-                let mut top = img[(x,y)];
-                let mut bottom = img[(x,y+1)];
-                blend_alpha(&mut top);
-                blend_alpha(&mut bottom);
-                write!(row, "\x1b[48;2;{};{};{}m\x1b[38;2;{};{};{}m▄",
-                       top[0], top[1], top[2],
-                       bottom[0], bottom[1], bottom[2]).unwrap();
+        let mut line = ANSIStrings(&strings).to_string();
+        if !reset {
+            let len = line.trim_end_matches(RESET).len();
+            line.truncate(len);
+        }
+        out.write_all(line.as_bytes())
+    }
+}
+
+/// The 256-colour style and glyph for `cell`. Fully transparent pixels are left in the
+/// terminal's default background, swapping to the opposite half block when only the
+/// foreground one is, rather than being blended with grey.
+fn ansi_style(cell: &Cell, cache: &mut ColourCache, matte: u8) -> (Style, &'static str) {
+    let transparent = |pixel: image::Rgba<u8>| pixel[3] == 0;
+    match cell.fg {
+        Some(fg) if transparent(fg) && transparent(cell.bg) => (Style::new(), " "),
+        Some(fg) if transparent(fg) && cell.glyph == "▄" => (Style::new().fg(Fixed(cache.index(cell.bg, matte))), "▀"),
+        Some(fg) if transparent(cell.bg) => (Style::new().fg(Fixed(cache.index(fg, matte))), cell.glyph),
+        Some(fg) => (Style::new().on(Fixed(cache.index(cell.bg, matte))).fg(Fixed(cache.index(fg, matte))), cell.glyph),
+        None if transparent(cell.bg) => (Style::new(), cell.glyph),
+        None => (Style::new().on(Fixed(cache.index(cell.bg, matte))), cell.glyph),
+    }
+}
+
+/// The top and bottom pixels of the half-block cell whose top pixel is at `(x, y)`.
+fn cell_pixels(img: &image::RgbaImage, x: u32, y: u32, vblend: bool) -> (image::Rgba<u8>, image::Rgba<u8>) {
+    let top = img[(x,y)];
+    let bottom = img[(x,y+1)];
+    if !vblend {
+        return (top, bottom);
+    }
+
+    let above = img[(x, y.saturating_sub(1))];
+    let below = img[(x, min(y + 2, img.height() - 1))];
+    let average = |a: image::Rgba<u8>, b: image::Rgba<u8>| a.map2(&b, |p, q| ((p as u16 + q as u16) / 2) as u8);
+    (average(top, above), average(bottom, below))
+}
+
+/// Quadrant glyphs, indexed by which of the top-left, top-right, bottom-left and
+/// bottom-right pixels (bits 0 to 3) are drawn in the foreground colour.
+static QUADRANTS: [&str; 16] = [
+    " ", "▘", "▝", "▀", "▖", "▌", "▞", "▛", "▗", "▚", "▐", "▜", "▄", "▙", "▟", "█",
+];
+
+/// Splits a 2×2 block of pixels, in reading order, between two colours picked by `method`.
+fn quadrant_cell(mut pixels: [image::Rgba<u8>; 4], matte: u8, method: CellColours, formula: LumaFormula) -> Cell {
+    for pixel in pixels.iter_mut() {
+        blend_alpha(pixel, matte);
+        pixel[3] = 255;
+    }
+    let brightness: Vec<f32> = pixels.iter().map(|p| luma([p[0], p[1], p[2]], formula)).collect();
+
+    let in_mask = |keep: &dyn Fn(usize) -> bool| (0..4).filter(|&i| keep(i)).fold(0, |mask, i| mask | (1 << i));
+    let mask = match method {
+        CellColours::TwoExtremes => {
+            let (darkest, brightest) = extremes(&brightness);
+            let (dark, bright) = (pixels[darkest], pixels[brightest]);
+            let mask = in_mask(&|i| colour_distance(&pixels[i], &bright) < colour_distance(&pixels[i], &dark));
+            return Cell {
+                glyph: QUADRANTS[mask],
+                fg: if mask == 0 { None } else { Some(bright) },
+                bg: dark,
+            };
+        }
+        CellColours::Average => {
+            let mean = brightness.iter().sum::<f32>() / 4.0;
+            in_mask(&|i| brightness[i] > mean)
+        }
+        CellColours::MedianCut => {
+            let widest = (0..3).max_by_key(|&c| {
+                let values = pixels.iter().map(|p| p[c]);
+                values.clone().max().unwrap() - values.min().unwrap()
+            }).unwrap();
+            let mut values: Vec<u16> = pixels.iter().map(|p| p[widest] as u16).collect();
+            values.sort_unstable();
+            // Pixels past the median, compared doubled to stay in whole numbers.
+            in_mask(&|i| pixels[i][widest] as u16 * 2 > values[1] + values[2])
+        }
+    };
+
+    let average = |in_foreground: bool| {
+        let group: Vec<_> = pixels.iter().enumerate()
+            .filter(|&(i, _)| (mask & (1 << i) != 0) == in_foreground)
+            .map(|(_, pixel)| pixel)
+            .collect();
+        let mut sum = [0u32; 3];
+        for pixel in &group {
+            for (total, &channel) in sum.iter_mut().zip(pixel.0.iter()) {
+                *total += channel as u32;
             }
+        }
+        let count = group.len().max(1) as u32;
+        image::Rgba([(sum[0] / count) as u8, (sum[1] / count) as u8, (sum[2] / count) as u8, 255])
+    };
 
-            write!(row, "\x1b[m\n").unwrap();
-            io::stdout().write(&row).unwrap();
-            row.clear();
+    Cell {
+        glyph: QUADRANTS[mask],
+        fg: if mask == 0 { None } else { Some(average(true)) },
+        bg: average(false),
+    }
+}
+
+/// The positions of the first darkest and first brightest of `brightness`.
+fn extremes(brightness: &[f32]) -> (usize, usize) {
+    let mut darkest = 0;
+    let mut brightest = 0;
+    for (i, &b) in brightness.iter().enumerate() {
+        if b < brightness[darkest] {
+            darkest = i;
+        }
+        if b > brightness[brightest] {
+            brightest = i;
         }
     }
+    (darkest, brightest)
+}
+
+fn colour_distance(a: &image::Rgba<u8>, b: &image::Rgba<u8>) -> i32 {
+    let dr = a[0] as i32 - b[0] as i32;
+    let dg = a[1] as i32 - b[1] as i32;
+    let db = a[2] as i32 - b[2] as i32;
+    dr * dr + dg * dg + db * db
+}
+
+/// Parses a palette file, either in GIMP `.gpl` format or with one `#rrggbb` colour per line.
+pub fn parse_palette(contents: &str) -> Result<Vec<[u8; 3]>, String> {
+    let mut lines = contents.lines().enumerate();
+    let gimp = contents.lines().next().map(str::trim) == Some("GIMP Palette");
+    if gimp {
+        lines.next();
+    }
+
+    let mut colours = Vec::new();
+    for (i, line) in lines {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if gimp {
+            if line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+                continue;
+            }
+            let mut values = line.split_whitespace().map(|v| v.parse::<u8>());
+            let mut colour = [0; 3];
+            for channel in colour.iter_mut() {
+                match values.next() {
+                    Some(Ok(value)) => *channel = value,
+                    _ => return Err(format!("line {}: expected three values from 0 to 255, got \"{}\"", i + 1, line)),
+                }
+            }
+            colours.push(colour);
+        } else {
+            match parse_colour(line) {
+                Ok(colour) => colours.push([colour[0], colour[1], colour[2]]),
+                Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+            }
+        }
+    }
+
+    if colours.is_empty() {
+        return Err("no colours found".to_string());
+    }
+    Ok(colours)
+}
+
+/// Parses a colour given as `#rrggbb`, `#rgb`, a CSS colour name such as `cornflowerblue`,
+/// or `transparent`.
+pub fn parse_colour(s: &str) -> Result<image::Rgba<u8>, String> {
+    let s = s.trim();
+    let name = s.to_ascii_lowercase();
+    if name == "transparent" {
+        return Ok(image::Rgba([0, 0, 0, 0]));
+    }
+    let rgb = match s.strip_prefix('#') {
+        Some(hex) => parse_hex_colour(hex),
+        None => COLOUR_NAMES.binary_search_by_key(&name.as_str(), |&(name, _)| name).ok()
+            .map(|i| COLOUR_NAMES[i].1)
+            // Palettes have always accepted rrggbb without the #.
+            .or_else(|| if s.len() == 6 { parse_hex_colour(s) } else { None }),
+    };
+    match rgb {
+        Some([r, g, b]) => Ok(image::Rgba([r, g, b, 255])),
+        None => Err(format!("Unknown colour: {} (expected #rrggbb, #rgb or a CSS colour name such as cornflowerblue)", s)),
+    }
+}
+
+fn parse_hex_colour(hex: &str) -> Option<[u8; 3]> {
+    if !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize, len: usize| u8::from_str_radix(&hex[i..i + len], 16).ok();
+    match hex.len() {
+        6 => Some([channel(0, 2)?, channel(2, 2)?, channel(4, 2)?]),
+        3 => Some([channel(0, 1)? * 17, channel(1, 1)? * 17, channel(2, 1)? * 17]),
+        _ => None,
+    }
+}
+
+/// The CSS named colours, sorted by name.
+static COLOUR_NAMES: [(&str, [u8; 3]); 148] = [
+    ("aliceblue", [240, 248, 255]),
+    ("antiquewhite", [250, 235, 215]),
+    ("aqua", [0, 255, 255]),
+    ("aquamarine", [127, 255, 212]),
+    ("azure", [240, 255, 255]),
+    ("beige", [245, 245, 220]),
+    ("bisque", [255, 228, 196]),
+    ("black", [0, 0, 0]),
+    ("blanchedalmond", [255, 235, 205]),
+    ("blue", [0, 0, 255]),
+    ("blueviolet", [138, 43, 226]),
+    ("brown", [165, 42, 42]),
+    ("burlywood", [222, 184, 135]),
+    ("cadetblue", [95, 158, 160]),
+    ("chartreuse", [127, 255, 0]),
+    ("chocolate", [210, 105, 30]),
+    ("coral", [255, 127, 80]),
+    ("cornflowerblue", [100, 149, 237]),
+    ("cornsilk", [255, 248, 220]),
+    ("crimson", [220, 20, 60]),
+    ("cyan", [0, 255, 255]),
+    ("darkblue", [0, 0, 139]),
+    ("darkcyan", [0, 139, 139]),
+    ("darkgoldenrod", [184, 134, 11]),
+    ("darkgray", [169, 169, 169]),
+    ("darkgreen", [0, 100, 0]),
+    ("darkgrey", [169, 169, 169]),
+    ("darkkhaki", [189, 183, 107]),
+    ("darkmagenta", [139, 0, 139]),
+    ("darkolivegreen", [85, 107, 47]),
+    ("darkorange", [255, 140, 0]),
+    ("darkorchid", [153, 50, 204]),
+    ("darkred", [139, 0, 0]),
+    ("darksalmon", [233, 150, 122]),
+    ("darkseagreen", [143, 188, 143]),
+    ("darkslateblue", [72, 61, 139]),
+    ("darkslategray", [47, 79, 79]),
+    ("darkslategrey", [47, 79, 79]),
+    ("darkturquoise", [0, 206, 209]),
+    ("darkviolet", [148, 0, 211]),
+    ("deeppink", [255, 20, 147]),
+    ("deepskyblue", [0, 191, 255]),
+    ("dimgray", [105, 105, 105]),
+    ("dimgrey", [105, 105, 105]),
+    ("dodgerblue", [30, 144, 255]),
+    ("firebrick", [178, 34, 34]),
+    ("floralwhite", [255, 250, 240]),
+    ("forestgreen", [34, 139, 34]),
+    ("fuchsia", [255, 0, 255]),
+    ("gainsboro", [220, 220, 220]),
+    ("ghostwhite", [248, 248, 255]),
+    ("gold", [255, 215, 0]),
+    ("goldenrod", [218, 165, 32]),
+    ("gray", [128, 128, 128]),
+    ("green", [0, 128, 0]),
+    ("greenyellow", [173, 255, 47]),
+    ("grey", [128, 128, 128]),
+    ("honeydew", [240, 255, 240]),
+    ("hotpink", [255, 105, 180]),
+    ("indianred", [205, 92, 92]),
+    ("indigo", [75, 0, 130]),
+    ("ivory", [255, 255, 240]),
+    ("khaki", [240, 230, 140]),
+    ("lavender", [230, 230, 250]),
+    ("lavenderblush", [255, 240, 245]),
+    ("lawngreen", [124, 252, 0]),
+    ("lemonchiffon", [255, 250, 205]),
+    ("lightblue", [173, 216, 230]),
+    ("lightcoral", [240, 128, 128]),
+    ("lightcyan", [224, 255, 255]),
+    ("lightgoldenrodyellow", [250, 250, 210]),
+    ("lightgray", [211, 211, 211]),
+    ("lightgreen", [144, 238, 144]),
+    ("lightgrey", [211, 211, 211]),
+    ("lightpink", [255, 182, 193]),
+    ("lightsalmon", [255, 160, 122]),
+    ("lightseagreen", [32, 178, 170]),
+    ("lightskyblue", [135, 206, 250]),
+    ("lightslategray", [119, 136, 153]),
+    ("lightslategrey", [119, 136, 153]),
+    ("lightsteelblue", [176, 196, 222]),
+    ("lightyellow", [255, 255, 224]),
+    ("lime", [0, 255, 0]),
+    ("limegreen", [50, 205, 50]),
+    ("linen", [250, 240, 230]),
+    ("magenta", [255, 0, 255]),
+    ("maroon", [128, 0, 0]),
+    ("mediumaquamarine", [102, 205, 170]),
+    ("mediumblue", [0, 0, 205]),
+    ("mediumorchid", [186, 85, 211]),
+    ("mediumpurple", [147, 112, 219]),
+    ("mediumseagreen", [60, 179, 113]),
+    ("mediumslateblue", [123, 104, 238]),
+    ("mediumspringgreen", [0, 250, 154]),
+    ("mediumturquoise", [72, 209, 204]),
+    ("mediumvioletred", [199, 21, 133]),
+    ("midnightblue", [25, 25, 112]),
+    ("mintcream", [245, 255, 250]),
+    ("mistyrose", [255, 228, 225]),
+    ("moccasin", [255, 228, 181]),
+    ("navajowhite", [255, 222, 173]),
+    ("navy", [0, 0, 128]),
+    ("oldlace", [253, 245, 230]),
+    ("olive", [128, 128, 0]),
+    ("olivedrab", [107, 142, 35]),
+    ("orange", [255, 165, 0]),
+    ("orangered", [255, 69, 0]),
+    ("orchid", [218, 112, 214]),
+    ("palegoldenrod", [238, 232, 170]),
+    ("palegreen", [152, 251, 152]),
+    ("paleturquoise", [175, 238, 238]),
+    ("palevioletred", [219, 112, 147]),
+    ("papayawhip", [255, 239, 213]),
+    ("peachpuff", [255, 218, 185]),
+    ("peru", [205, 133, 63]),
+    ("pink", [255, 192, 203]),
+    ("plum", [221, 160, 221]),
+    ("powderblue", [176, 224, 230]),
+    ("purple", [128, 0, 128]),
+    ("rebeccapurple", [102, 51, 153]),
+    ("red", [255, 0, 0]),
+    ("rosybrown", [188, 143, 143]),
+    ("royalblue", [65, 105, 225]),
+    ("saddlebrown", [139, 69, 19]),
+    ("salmon", [250, 128, 114]),
+    ("sandybrown", [244, 164, 96]),
+    ("seagreen", [46, 139, 87]),
+    ("seashell", [255, 245, 238]),
+    ("sienna", [160, 82, 45]),
+    ("silver", [192, 192, 192]),
+    ("skyblue", [135, 206, 235]),
+    ("slateblue", [106, 90, 205]),
+    ("slategray", [112, 128, 144]),
+    ("slategrey", [112, 128, 144]),
+    ("snow", [255, 250, 250]),
+    ("springgreen", [0, 255, 127]),
+    ("steelblue", [70, 130, 180]),
+    ("tan", [210, 180, 140]),
+    ("teal", [0, 128, 128]),
+    ("thistle", [216, 191, 216]),
+    ("tomato", [255, 99, 71]),
+    ("turquoise", [64, 224, 208]),
+    ("violet", [238, 130, 238]),
+    ("wheat", [245, 222, 179]),
+    ("white", [255, 255, 255]),
+    ("whitesmoke", [245, 245, 245]),
+    ("yellow", [255, 255, 0]),
+    ("yellowgreen", [154, 205, 50]),
+];
+
+/// The 256-colour palette index to print for `pixel`.
+fn ansi_index(mut pixel: image::Rgba<u8>, matte: u8) -> u8 {
+    blend_alpha(&mut pixel, matte);
+    find_colour_index(pixel.to_rgb().channels())
 }
 
 fn find_colour_index(pixel: &[u8]) -> u8 {
+    // Skip the first 16 colours, which terminals tend to customise.
+    (16 + nearest_colour(pixel, &ANSI_COLOURS[16..255])) as u8
+}
+
+fn nearest_colour<C: Copy + Into<i32>>(pixel: &[u8], colours: &[[C; 3]]) -> usize {
     let mut best = 0;
     let mut best_distance = 255 * 255 * 3 + 1;
-    for i in 16..255 {
-        let ansi_colour = ANSI_COLOURS[i];
-        let dr = ansi_colour[0] - pixel[0] as i32;
-        let dg = ansi_colour[1] - pixel[1] as i32;
-        let db = ansi_colour[2] - pixel[2] as i32;
+    for (i, colour) in colours.iter().enumerate() {
+        let dr = colour[0].into() - pixel[0] as i32;
+        let dg = colour[1].into() - pixel[1] as i32;
+        let db = colour[2].into() - pixel[2] as i32;
         let distance = dr * dr + dg * dg + db * db;
 
         if distance < best_distance {
             best_distance = distance;
-            best = i as u8;
+            best = i;
         }
     }
 
-    return best;
+    best
 }
 
-fn blend_alpha(pixel: &mut image::Rgba<u8>) {
+fn blend_alpha(pixel: &mut image::Rgba<u8>, matte: u8) {
     let alpha = pixel[3] as i32 as f32/255.0;
-    pixel[0] = (alpha*(pixel[0] as i32 as f32) + (1.0 - alpha)*38.0) as u8;
-    pixel[1] = (alpha*(pixel[1] as i32 as f32) + (1.0 - alpha)*38.0) as u8;
-    pixel[2] = (alpha*(pixel[2] as i32 as f32) + (1.0 - alpha)*38.0) as u8;
+    let matte = matte as f32;
+    pixel[0] = (alpha*(pixel[0] as i32 as f32) + (1.0 - alpha)*matte) as u8;
+    pixel[1] = (alpha*(pixel[1] as i32 as f32) + (1.0 - alpha)*matte) as u8;
+    pixel[2] = (alpha*(pixel[2] as i32 as f32) + (1.0 - alpha)*matte) as u8;
 }
 
 static ANSI_COLOURS: [[i32; 3]; 256] = [
@@ -135,4 +1718,94 @@ static ANSI_COLOURS: [[i32; 3]; 256] = [
 [ 0xbc, 0xbc, 0xbc ],[ 0xc6, 0xc6, 0xc6 ],[ 0xd0, 0xd0, 0xd0 ],[ 0xda, 0xda, 0xda ],[ 0xe4, 0xe4, 0xe4 ],
 [ 0xee, 0xee, 0xee ]];
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(level: u8) -> image::Rgba<u8> {
+        image::Rgba([level, level, level, 255])
+    }
+
+    #[test]
+    fn colour_cache_agrees_with_searching_the_palette() {
+        let mut cache = ColourCache::new();
+        // More colours than slots, so some replace others, then the first few again.
+        let colours: Vec<u32> = (0..1u32 << 24).step_by(241).collect();
+        for &rgb in colours.iter().chain(&colours[..1000]) {
+            let pixel = image::Rgba([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 255]);
+            assert_eq!(cache.index(pixel, 0), find_colour_index(&pixel.0[..3]), "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn cell_colours_split_a_gradient_differently() {
+        // Dark on top, brightening unevenly towards the bottom right.
+        let cell = |method| quadrant_cell([grey(0), grey(20), grey(40), grey(255)], 0, method, LumaFormula::Rec601);
+
+        let two_extremes = cell(CellColours::TwoExtremes);
+        assert_eq!((two_extremes.glyph, two_extremes.fg, two_extremes.bg), ("▗", Some(grey(255)), grey(0)));
+
+        let average = cell(CellColours::Average);
+        assert_eq!((average.glyph, average.fg, average.bg), ("▗", Some(grey(255)), grey(20)));
 
+        let median_cut = cell(CellColours::MedianCut);
+        assert_eq!((median_cut.glyph, median_cut.fg, median_cut.bg), ("▄", Some(grey(147)), grey(10)));
+    }
+
+    #[test]
+    fn deuteranopia_turns_red_olive() {
+        let simulated = |rgb: [u8; 3]| {
+            let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba([rgb[0], rgb[1], rgb[2], 255])));
+            simulate_deficiency(&img, Deficiency::Deuteranopia).to_rgba()[(0, 0)]
+        };
+        // Red and green are confused, so red loses its hue and comes out a dull olive.
+        assert_eq!(simulated([255, 0, 0]), image::Rgba([147, 147, 0, 255]));
+        assert_eq!(simulated([255, 255, 255]), image::Rgba([255, 255, 255, 255]));
+        assert_eq!(simulated([0, 0, 255]), image::Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn transparent_cells_keep_the_default_background() {
+        // A checkerboard of opaque cells, red over blue, and fully transparent ones.
+        let img = image::RgbaImage::from_fn(3, 4, |x, y| match ((x + y / 2) % 2, y % 2) {
+            (0, 0) => image::Rgba([255, 0, 0, 255]),
+            (0, _) => image::Rgba([0, 0, 255, 255]),
+            _ => image::Rgba([0, 0, 0, 0]),
+        });
+        let mut out = Vec::new();
+        write_resized(&mut out, &img, &RenderOptions::default()).unwrap();
+        let opaque = "\x1b[48;5;196;38;5;21m▄\x1b[0m";
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{0} {0}\n {0} \n", opaque));
+    }
+
+    #[test]
+    fn ansi_style_leaves_transparent_halves_in_the_default_background() {
+        let (red, clear) = (image::Rgba([255, 0, 0, 255]), image::Rgba([0, 0, 0, 0]));
+        let mut cache = ColourCache::default();
+        let mut style = |fg, bg| ansi_style(&Cell { glyph: "▄", fg: Some(fg), bg }, &mut cache, Theme::Dark.matte());
+        assert_eq!(style(red, clear), (Style::new().fg(Fixed(196)), "▄"));
+        assert_eq!(style(clear, red), (Style::new().fg(Fixed(196)), "▀"));
+        assert_eq!(style(clear, clear), (Style::new(), " "));
+        assert_eq!(style(red, red), (Style::new().on(Fixed(196)).fg(Fixed(196)), "▄"));
+    }
+
+    #[test]
+    fn grey_and_alpha_images_render_like_rgba() {
+        // An opaque grey pixel over a fully transparent one.
+        let img = image::DynamicImage::ImageLumaA8(image::ImageBuffer::from_fn(1, 2, |_, y| {
+            image::LumaA([200, if y == 0 { 255 } else { 0 }])
+        }));
+        let resized = resize_image(&img, &RenderOptions::default(), 1, 2);
+        assert_eq!(resized[(0, 0)], image::Rgba([200, 200, 200, 255]));
+        assert_eq!(resized[(0, 1)][3], 0);
+
+        let render = |options: RenderOptions| String::from_utf8(render_bytes(&img, &options, 1, 2)).unwrap();
+        // The transparent half is left in the terminal's own background...
+        assert_eq!(render(RenderOptions::default()), "\x1b[38;5;251m▀\x1b[0m\n");
+        // ...or, with 24-bit colour, blended into the theme's background.
+        let true_colour = RenderOptions { true_colour: true, ..RenderOptions::default() };
+        assert_eq!(render(true_colour.clone()), "\x1b[48;2;200;200;200m\x1b[38;2;38;38;38m▄\x1b[m\n");
+        let light = RenderOptions { theme: Theme::Light, ..true_colour };
+        assert_eq!(render(light), "\x1b[48;2;200;200;200m\x1b[38;2;217;217;217m▄\x1b[m\n");
+    }
+}
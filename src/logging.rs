@@ -0,0 +1,47 @@
+//! A small stderr logger for the `logging` feature, configured like env_logger with
+//! `RUST_LOG`, e.g. `RUST_LOG=termpix=debug` or `RUST_LOG=trace`.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("termpix")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("termpix: {}: {}", record.level().to_string().to_lowercase(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Starts logging at the level `RUST_LOG` gives for termpix, if any. Directives for other
+/// crates are ignored, as only termpix logs through this.
+pub fn init() {
+    let directives = std::env::var("RUST_LOG").unwrap_or_default();
+    let mut level = LevelFilter::Off;
+    for directive in directives.split(',').map(str::trim) {
+        let (target, directive_level) = match directive.find('=') {
+            Some(equals) => (Some(&directive[..equals]), &directive[equals + 1..]),
+            None => (None, directive),
+        };
+        if target.is_some_and(|target| target != "termpix") {
+            continue;
+        }
+        match directive_level.parse::<Level>() {
+            Ok(directive_level) => level = directive_level.to_level_filter(),
+            // A bare target, as in `RUST_LOG=termpix`, turns everything on.
+            Err(_) if target.is_none() && directive_level == "termpix" => level = LevelFilter::Trace,
+            Err(_) => (),
+        }
+    }
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -1,56 +1,283 @@
 extern crate docopt;
 extern crate image;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(feature = "logging")]
+#[macro_use]
+extern crate log;
+extern crate rand;
 extern crate resvg;
 extern crate usvg;
+#[cfg(feature = "archive")]
+extern crate zip;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate terminal_size;
 extern crate termpix;
 
-use std::io::Write;
+/// Without the `logging` feature, debug events compile to nothing.
+#[cfg(not(feature = "logging"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+#[cfg(feature = "logging")]
+mod logging;
+#[cfg(unix)]
+mod screen;
+mod svg_backend;
 
 use docopt::Docopt;
 use image::GenericImageView;
 use image::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
 use terminal_size::{terminal_size, Height, Width};
 
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
-const USAGE: &'static str = "
-    termpix : display image from <file> in an ANSI terminal
+const USAGE: &str = "
+    termpix : display image from <file> (or stdin, if <file> is -) in an ANSI terminal
 
     Usage:
-      termpix <file> [--width <width>] [--height <height>] [--max-width <max-width>] [--max-height <max-height>] [--true-color|--true-colour] [--filter <nearest|triangle|catmullrom|gaussian|lanczos3>]
+      termpix <file> [options]
+      termpix --list <list> [options]
+      termpix --stack <files>... [options]
+      termpix --test-pattern <pattern> [options]
 
       By default it will use as much of the current terminal window as possible, while maintaining the aspect 
-      ratio of the input image. This can be overridden as follows.
+      ratio of the input image. This can be overridden as follows. When there's no terminal,
+      e.g. in a previewer, set TERMPIX_COLS and TERMPIX_ROWS to the size to use instead; the
+      options below take precedence over them.
 
     Options:
       --width <width>    Output width in terminal columns.
       --height <height>  Output height in terminal rows.
       --max-width <max-width>  Maximum width to use when --width is excluded
       --max-height <max-height>  Maximum height to use when --height is excluded
+      --geometry <geometry>  Width and height as one option, ImageMagick style: <w>x<h>,
+                         <w>x or x<h>, optionally followed by +<x>+<y> to draw <x>
+                         columns right of and <y> rows below the cursor.
       --true-colour             Use 24-bit RGB colour. Some terminals don't support this.
       --true-color             Use 24-bit RGB color but you don't spell so good.
-      --filter <filter>
+      --sgr <syntax>     Separate the parts of 24-bit colour escapes with semicolon (the
+                         default) or colon, for terminals that only understand one.
+      --filter <filter>  Resize filter: nearest, triangle, catmullrom, gaussian or lanczos3.
+      --fast             Resize with a quick box filter instead of --filter. Lower quality,
+                         but much faster for large images.
+      --bilinear         Sample each pixel from the original image by bilinear
+                         interpolation instead of --filter. Steadier at odd scales, but slower.
+      --text-optimize    Sharpen before and after resizing to keep small text readable.
+                         Best effort, for screenshots rather than photos.
+      --unit <unit>      Whether --width, --height and their --max- versions count cells
+                         or pixels of the drawn image [default: cells].
+      --no-filter        Draw the image's pixels as they are, without resizing. Fails
+                         if the output size doesn't match the image.
+      --crop-aspect <ratio>  Crop the image to an aspect ratio such as 1:1 or 16:9,
+                         keeping its centre, before fitting it.
+      --fit <side>       Fill the terminal's width or height, and let the other side
+                         follow the aspect ratio even if it doesn't fit.
+      --keep-aspect <keep-aspect>  true or false. Force the aspect ratio to be kept (fitting
+                         inside --width and --height when both are given) or ignored
+                         (filling the terminal in any dimension that isn't given).
+      --pixel-aspect <ratio>  Width of each pixel of the image relative to its height, for
+                         images with non-square pixels such as anamorphic video frames.
+      --mode <mode>      How to draw pixels in cells: half-block (the default, two per
+                         cell), bg-only or quadrant.
+      --cell-colors <method>  How quadrant mode picks the two colours of each cell:
+                         two-extremes (the default, crisp for text), average or
+                         median-cut (smoother gradients).
+      --bg-only          Draw one pixel per cell using only the background colour.
+      --palette <palette>  Only use the colours in <palette>, a GIMP .gpl file or one
+                         colour per line, as #rrggbb or a CSS name. Printed as 24-bit colour.
+      --max-colors <n>   Only use the <n> colours that best fit each image, printed as
+                         24-bit colour.
+      --vblend           Blend each half-block pixel with its neighbour in the next cell
+                         for smoother tone but less vertical detail.
+      --test-pattern <pattern>  Draw a built-in image instead of a file, to check the
+                         terminal's colours: colorbars, gradient, grayscale-ramp or palette.
+      --format <format>  Decode the file as <format> (png, jpeg, gif, webp, bmp, tga, svg...)
+                         instead of guessing from its name or contents.
+      --svg-max-pixels <n>  Refuse to load SVGs that would rasterize to more than <n> pixels.
+      --inspect <x,y>    Print the colour of the pixel at <x,y> in the original image
+                         instead of drawing it.
+      --hash <hash>      Print the ahash, dhash or phash perceptual hash of the image as
+                         hex instead of drawing it.
+      --dry-run          Load the image and print the size it would be drawn at, without drawing it.
+      --verbose          Print timings and output size to stderr.
+      --json             Print sizes and timings for each image to stderr as a line of
+                         JSON. With --dry-run, instead of the usual summary.
+      --fd <n>           Draw to file descriptor <n> instead of stdout (Unix only).
+      --alt-screen       Draw on the terminal's alternate screen, and go back to the
+                         normal one on exit, leaving the scrollback alone (Unix only).
+      --wait             Wait for a key press before exiting (Unix only).
+      --sparse           Skip over fully transparent cells instead of drawing them, to
+                         overlay the image on what's already in the terminal.
+      --flush-rows <n>   Send the image to the terminal <n> rows at a time, so it appears
+                         as it's drawn, or all at once with 0 [default: 4].
+      --no-reset         Leave the colours of the last cell set after drawing.
+      --only-region <x,y,w,h>  Only draw the <w>x<h> cells at column <x>, row <y> of the
+                         image, starting at the cursor, to redraw part of it in place.
+      --loop <n>         Play animations <n> times, 0 meaning forever. Defaults to the
+                         count stored in the file.
+      --fps <fps>        Play animations at <fps> frames per second instead of the
+                         stored frame delays.
+      --quadrant         Draw four pixels per cell using quadrant block glyphs.
+      --frames <frames>  Only play these animation frames, e.g. 2..5 or 0,3,5.
+      --hold <ms>        Pause on the last frame of an animation for <ms> milliseconds
+                         before playing it again.
+      --record <cast>    Write animations to <cast> as an asciinema recording instead of
+                         playing them.
+      --full-redraw      Draw every frame of an animation in full, instead of only the
+                         cells that change.
+      --progress         Show how many animation frames have been rendered while they're
+                         prepared.
+      --once             Play animations once and stop on the last frame. --loop takes
+                         precedence.
+      --channel <channel>  Only draw one channel, in grey: r, g, b, a or luma. With a,
+                         opaque areas are white and transparent ones black.
+      --luma <formula>   Work out brightness, for --channel luma and --hash, with rec601,
+                         rec709 (the default) or average.
+      --simulate <deficiency>  Show the image as it looks with protanopia, deuteranopia
+                         or tritanopia.
+      --pad-even         Pad images with an odd number of pixel rows with a transparent
+                         row, instead of cutting the last one off. May add a row of cells.
+      --theme <theme>    Whether the terminal background is dark or light, for blending
+                         transparent pixels into. auto guesses from COLORFGBG, and
+                         falls back on dark [default: auto].
+      --checker          Draw transparent areas over a grey checkerboard.
+      --checker-size <cells>  Width of the checkerboard squares. Defaults to 4.
+      --checker-shades <shades>  Grey levels of the squares, from 0 to 255. Defaults to
+                         153,102.
+      --legend           After drawing, list the colours used on stderr, with their hex
+                         values and 256-colour indices. Animations list the colours of
+                         every frame before playing.
+      --copy             After drawing, also copy the escape codes to the clipboard
+                         with OSC 52, for terminals that allow it. Animations copy
+                         their last frame.
+      --deadline <secs>  Give up if termpix is still running after <secs> seconds,
+                         including any animation playback. Part of the image may
+                         already have been drawn.
+      --quiet            Don't print warnings.
+      --list <list>      Render every image named in <list>, one path per line.
+                         Blank lines and lines starting with # are ignored.
+      --stack            Draw each of <files> in turn, one above the other, at the full
+                         width of the terminal. The result may be taller than it.
+      --gap <rows>       With --stack, leave <rows> blank rows between the images [default: 0].
+      --slideshow-loop <n>  Go through the --list <n> times, or forever.
+      --shuffle          Go through the --list in a random order each time.
+      --seed <seed>      Seed the --shuffle order, to repeat it.
+
+    Development:
+      --iterations <n>   Resize and draw the image <n> times without printing it, and
+                         report the average times.
+
+    Exit status:
+      0  Success.
+      2  Invalid arguments.
+      3  File not found.
+      4  The image could not be decoded.
+      5  Unsupported image format or feature.
+      6  The --deadline passed.
+      7  The --record file could not be written.
 ";
 
-#[derive(Debug, Deserialize)]
+const EXIT_USAGE: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_DECODE: i32 = 4;
+const EXIT_UNSUPPORTED: i32 = 5;
+const EXIT_DEADLINE: i32 = 6;
+const EXIT_WRITE: i32 = 7;
+
+#[derive(Debug, Clone, Deserialize)]
 struct Args {
     flag_width: Option<u32>,
     flag_height: Option<u32>,
     flag_max_width: Option<u32>,
     flag_max_height: Option<u32>,
+    flag_unit: String,
+    flag_geometry: Option<String>,
     flag_true_colour: bool,
     flag_true_color: bool,
+    flag_sgr: Option<String>,
     flag_filter: Option<String>,
+    flag_fast: bool,
+    flag_bilinear: bool,
+    flag_text_optimize: bool,
+    flag_no_filter: bool,
+    flag_channel: Option<String>,
+    flag_luma: Option<String>,
+    flag_simulate: Option<String>,
+    flag_pad_even: bool,
+    flag_fit: Option<String>,
+    flag_keep_aspect: Option<String>,
+    flag_pixel_aspect: Option<f32>,
+    flag_mode: Option<String>,
+    flag_cell_colors: Option<String>,
+    flag_bg_only: bool,
+    flag_quadrant: bool,
+    flag_quiet: bool,
+    flag_deadline: Option<f64>,
+    flag_legend: bool,
+    flag_copy: bool,
+    flag_verbose: bool,
+    flag_progress: bool,
+    flag_full_redraw: bool,
+    flag_json: bool,
+    flag_iterations: Option<u32>,
+    flag_dry_run: bool,
+    flag_inspect: Option<String>,
+    flag_hash: Option<String>,
+    flag_no_reset: bool,
+    flag_sparse: bool,
+    flag_flush_rows: usize,
+    flag_only_region: Option<String>,
+    flag_crop_aspect: Option<String>,
+    flag_fd: Option<i32>,
+    flag_alt_screen: bool,
+    flag_wait: bool,
+    flag_loop: Option<u32>,
+    flag_fps: Option<f32>,
+    flag_frames: Option<String>,
+    flag_hold: Option<u64>,
+    flag_once: bool,
+    flag_record: Option<String>,
+    flag_palette: Option<String>,
+    flag_max_colors: Option<usize>,
+    flag_vblend: bool,
+    flag_theme: String,
+    flag_checker: bool,
+    flag_checker_size: Option<u32>,
+    flag_checker_shades: Option<String>,
+    flag_svg_max_pixels: Option<u64>,
+    flag_format: Option<String>,
+    flag_test_pattern: Option<String>,
+    flag_list: Option<String>,
+    flag_slideshow_loop: Option<String>,
+    flag_shuffle: bool,
+    flag_seed: Option<u64>,
+    flag_stack: bool,
+    flag_gap: u32,
     arg_file: String,
+    arg_files: Vec<String>,
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum LoadImageError {
     SvgError(String),
     ImageError(image::ImageError),
+    #[cfg(feature = "archive")]
+    ArchiveError(String),
 }
 
 impl std::fmt::Display for LoadImageError {
@@ -58,110 +285,1413 @@ impl std::fmt::Display for LoadImageError {
         match &self {
             LoadImageError::SvgError(msg) => write!(f, "{}", msg),
             LoadImageError::ImageError(err) => err.fmt(f),
+            #[cfg(feature = "archive")]
+            LoadImageError::ArchiveError(msg) => write!(f, "{}", msg),
         }
     }
 }
 impl std::error::Error for LoadImageError {}
+impl LoadImageError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            LoadImageError::ImageError(ImageError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => EXIT_NOT_FOUND,
+            LoadImageError::ImageError(ImageError::Unsupported(_)) => EXIT_UNSUPPORTED,
+            LoadImageError::SvgError(_) | LoadImageError::ImageError(_) => EXIT_DECODE,
+            #[cfg(feature = "archive")]
+            LoadImageError::ArchiveError(_) => EXIT_DECODE,
+        }
+    }
+}
 impl From<image::ImageError> for LoadImageError {
     fn from(e: image::ImageError) -> Self {
         LoadImageError::ImageError(e)
     }
 }
 
-fn get_image(path: &String) -> std::result::Result<DynamicImage, LoadImageError> {
+/// Length in pixels of the longer side of a rasterized SVG.
+const SVG_RASTER_SIZE: u32 = 1000;
+
+/// A decoder chosen with `--format`.
+#[derive(Debug, Clone, Copy)]
+enum InputFormat {
+    Svg,
+    Image(ImageFormat),
+}
+
+fn get_format(name: &str) -> Option<InputFormat> {
+    match name {
+        "svg" => Some(InputFormat::Svg),
+        // The image crate only looks formats up by file extension.
+        _ => ImageFormat::from_path(format!("image.{}", name)).ok().map(InputFormat::Image),
+    }
+}
+
+fn get_image(path: &String, svg_max_pixels: Option<u64>, format: Option<InputFormat>) -> std::result::Result<DynamicImage, LoadImageError> {
+    if let Some(format) = format {
+        debug!("decoding {} as {:?}, as --format says", path, format);
+    }
+    match format {
+        Some(InputFormat::Svg) => {
+            return get_svg(&read_source(path)?, svg_max_pixels);
+        }
+        Some(InputFormat::Image(ImageFormat::Tga)) => return get_tga(&read_source(path)?),
+        Some(InputFormat::Image(format)) => {
+            return Ok(image::load_from_memory_with_format(&read_source(path)?, format)?);
+        }
+        None if path == "-" => {
+            debug!("guessing the format of stdin from its contents");
+            return Ok(image::load_from_memory(&read_source(path)?)?);
+        }
+        None => (),
+    }
+
     if path.ends_with(".svg") {
-        let svg_root = usvg::Tree::from_file(path, &usvg::Options::default());
-        if let Err(_) = svg_root {
-            return Err(LoadImageError::SvgError("Failed to load svg".to_string()));
-        }
-        let svg_root = svg_root.unwrap();
-        let svg_image = resvg::render(&svg_root, usvg::FitTo::Width(1000), None);
-        if let Some(svg_image) = svg_image {
-            let mut dyn_img = DynamicImage::new_rgba8(svg_image.width(), svg_image.height());
-            let data = svg_image.data();
-            for x in 0..svg_image.width() {
-                for y in 0..svg_image.height() {
-                    let ind: usize = ((y * svg_image.width() + x) * 4) as usize;
-                    let r = data[ind];
-                    let g = data[ind + 1];
-                    let b = data[ind + 2];
-                    let a = data[ind + 3];
-
-                    dyn_img.put_pixel(x, y, image::Rgba([r, g, b, a]))
-                }
-            }
-            return Ok(dyn_img);
+        debug!("decoding {} as SVG, from its name", path);
+        return get_svg(&read_source(path)?, svg_max_pixels);
+    }
+
+    if path.to_lowercase().ends_with(".tga") {
+        debug!("decoding {} as TGA, from its name", path);
+        return get_tga(&read_source(path)?);
+    }
+
+    #[cfg(feature = "archive")]
+    {
+        if split_archive_path(path).is_some() {
+            debug!("reading {} from an archive and guessing its format from its contents", path);
+            return Ok(image::load_from_memory(&read_source(path)?)?);
         }
     }
     Ok(image::open(path)?)
 }
 
-fn get_filter(str: String) -> Option<imageops::FilterType> {
-    match str.as_str() {
-        "nearest" => Some(imageops::Nearest),
-        "triangle" => Some(imageops::Triangle),
-        "catmullrom" => Some(imageops::CatmullRom),
-        "gaussian" => Some(imageops::Gaussian),
-        "lanczos3" => Some(imageops::Lanczos3),
+fn get_svg(data: &[u8], svg_max_pixels: Option<u64>) -> std::result::Result<DynamicImage, LoadImageError> {
+    svg_backend::rasterize(data, SVG_RASTER_SIZE, svg_max_pixels)
+        .map(DynamicImage::ImageRgba8)
+        .map_err(LoadImageError::SvgError)
+}
+
+/// The image descriptor bit saying a TGA's pixels are stored right to left.
+const TGA_RIGHT_TO_LEFT: u8 = 0x10;
+
+fn get_tga(data: &[u8]) -> std::result::Result<DynamicImage, LoadImageError> {
+    let img = image::load_from_memory_with_format(data, ImageFormat::Tga)?;
+    // The decoder handles bottom-up files itself, but not right-to-left ones.
+    if data.len() > 17 && data[17] & TGA_RIGHT_TO_LEFT != 0 {
+        return Ok(img.fliph());
+    }
+    Ok(img)
+}
+
+/// Splits `archive.zip:member` into the archive path and the path of the member inside it.
+#[cfg(feature = "archive")]
+fn split_archive_path(path: &str) -> Option<(&str, &str)> {
+    // Only ASCII is lowercased, so byte offsets still line up with `path`.
+    let at = path.to_ascii_lowercase().find(".zip:")? + ".zip".len();
+    Some((&path[..at], &path[at + 1..]))
+}
+
+#[cfg(feature = "archive")]
+fn read_archive_member(archive: &str, member: &str) -> std::result::Result<Vec<u8>, LoadImageError> {
+    let file = std::fs::File::open(archive).map_err(ImageError::IoError)?;
+    let mut zip = zip::ZipArchive::new(file)
+        .map_err(|e| LoadImageError::ArchiveError(format!("Failed to read {}: {}", archive, e)))?;
+    let mut entry = match zip.by_name(member) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => {
+            return Err(ImageError::IoError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found in {}", member, archive))).into());
+        }
+        Err(e) => return Err(LoadImageError::ArchiveError(format!("Failed to read {} from {}: {}", member, archive, e))),
+    };
+
+    let mut data = Vec::new();
+    io::Read::read_to_end(&mut entry, &mut data)
+        .map_err(|e| LoadImageError::ArchiveError(format!("Failed to read {} from {}: {}", member, archive, e)))?;
+    Ok(data)
+}
+
+/// Reads the whole of `path`, which may be `-` for stdin or name a member of a zip archive.
+fn read_source(path: &str) -> std::result::Result<Vec<u8>, LoadImageError> {
+    if path == "-" {
+        let mut data = Vec::new();
+        io::Read::read_to_end(&mut io::stdin(), &mut data).map_err(ImageError::IoError)?;
+        return Ok(data);
+    }
+    #[cfg(feature = "archive")]
+    {
+        if let Some((archive, member)) = split_archive_path(path) {
+            return read_archive_member(archive, member);
+        }
+    }
+    Ok(std::fs::read(path).map_err(ImageError::IoError)?)
+}
+
+/// A decoded animation, each frame already composited onto the full canvas.
+struct Animation {
+    frames: Vec<Frame>,
+    /// Number of times to play the animation, 0 meaning forever.
+    loops: u32,
+}
+
+/// Decodes `path` as an animated PNG, or returns `None` if it isn't one. With `format`,
+/// only files it says are PNGs are tried, whatever their extension.
+fn get_animation(path: &str, format: Option<InputFormat>) -> std::result::Result<Option<Animation>, LoadImageError> {
+    let png = match format {
+        Some(format) => matches!(format, InputFormat::Image(ImageFormat::Png)),
+        None => path.to_lowercase().ends_with(".png"),
+    };
+    // Stdin can only be read once, so it's always decoded as a still image.
+    if path == "-" || !png {
+        return Ok(None);
+    }
+
+    let data = read_source(path)?;
+    let decoder = png::PngDecoder::new(io::Cursor::new(&data))?;
+    if !decoder.is_apng() {
+        return Ok(None);
+    }
+    let frames = decoder.apng().into_frames().collect_frames()?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(Animation {
+        frames,
+        loops: apng_num_plays(&data).unwrap_or(0),
+    }))
+}
+
+/// Reads `num_plays` from the acTL chunk of a PNG file.
+fn apng_num_plays(data: &[u8]) -> Option<u32> {
+    let read_u32 = |at: usize| {
+        data.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    // Skip the signature, then walk the chunks up to the image data.
+    let mut at = 8;
+    while let Some(length) = read_u32(at) {
+        match data.get(at + 4..at + 8) {
+            Some(b"acTL") => return read_u32(at + 12),
+            Some(b"IDAT") | None => return None,
+            _ => at += 12 + length as usize,
+        }
+    }
+    None
+}
+
+fn play_animation(args: &Args, animation: &Animation, decode_time: Duration, options: &termpix::RenderOptions, keep_aspect: Option<bool>, out: &mut dyn Write) {
+    let images: Vec<_> = animation.frames.iter()
+        .map(|frame| crop_aspect(args, DynamicImage::ImageRgba8(frame.buffer().clone())))
+        .collect();
+    let (orig_width, orig_height) = images[0].dimensions();
+    let pixels_per_row = options.mode.pixels_per_row();
+    let (width, height) = determine_size(args, keep_aspect, pixels_per_row, orig_width, orig_height);
+    check_no_filter(args, options, orig_width, orig_height, width, height);
+    let columns = visible_columns(args, width);
+
+    // Render every frame up front, so each loop only has to write out the bytes. Unless
+    // --full-redraw is given, frames after the first only redraw the cells that change.
+    let (mut resize_time, mut render_time) = (Duration::default(), Duration::default());
+    let mut resized_frames: Vec<image::RgbaImage> = Vec::with_capacity(images.len());
+    // Frames mostly share their colours, so each one's 256-colour lookup is only done once.
+    let mut renderer = termpix::Renderer::new(options.clone());
+    let frames: Vec<_> = animation.frames.iter().zip(&images).enumerate().map(|(i, (frame, img))| {
+        let delay = match args.flag_fps {
+            Some(fps) => Duration::from_secs_f32(1.0 / fps),
+            None => {
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                Duration::from_millis((numer / denom.max(1)) as u64)
+            }
+        };
+        let start = Instant::now();
+        let resized = clip_columns(termpix::resize_image(img, options, width, height), options, columns);
+        resize_time += start.elapsed();
+
+        let start = Instant::now();
+        let rendered = match resized_frames.last() {
+            Some(previous) if !args.flag_full_redraw => renderer.render_changes(previous, &resized),
+            _ => renderer.render_resized(&resized),
+        }.as_bytes().to_vec();
+        render_time += start.elapsed();
+        resized_frames.push(resized);
+        if args.flag_progress {
+            show_progress(i + 1, animation.frames.len());
+        }
+        (rendered, delay)
+    }).collect();
+    // What to draw over the last frame to start the animation again.
+    let start = Instant::now();
+    let restart = if args.flag_full_redraw {
+        frames[0].0.clone()
+    } else {
+        renderer.render_changes(&resized_frames[resized_frames.len() - 1], &resized_frames[0]).as_bytes().to_vec()
+    };
+    render_time += start.elapsed();
+    if args.flag_verbose {
+        let elapsed = resize_time + render_time;
+        eprintln!("termpix: rendered {} frames in {:.1?} ({:.1?} per frame)",
+                  frames.len(), elapsed, elapsed / frames.len() as u32);
+    }
+    if args.flag_json {
+        let mut stats = Stats::new(&args.arg_file, (orig_width, orig_height), options, columns, height, decode_time);
+        stats.frames = frames.len();
+        stats.resize_ms = Some(millis(resize_time));
+        stats.render_ms = Some(millis(render_time));
+        stats.bytes = Some(frames.iter().map(|(rendered, _)| rendered.len()).sum());
+        stats.print();
+    }
+
+    let loops = match args.flag_loop {
+        Some(loops) => loops,
+        None if args.flag_once => 1,
+        None => animation.loops,
+    };
+    let hold = Duration::from_millis(args.flag_hold.unwrap_or(0));
+    let rewind = rewind(options, columns, height);
+
+    if let Some(ref path) = args.flag_record {
+        let rows = output_rows(options, width, height);
+        // The screen has to fit the offset as well, or players would wrap and scroll.
+        let (x_offset, y_offset) = if options.region.is_some() { (0, 0) } else { options.offset };
+        write_cast(path, &frames, &restart, loops, hold, &rewind, (columns + x_offset, rows + y_offset)).unwrap_or_else(|e| {
+            eprintln!("Could not write {}: {}", path, e);
+            std::process::exit(EXIT_WRITE)
+        });
+        return;
+    }
+
+    // An animation may loop forever, so the legend and the copy come before it plays.
+    if args.flag_legend {
+        let mut colours: Vec<_> = resized_frames.iter()
+            .flat_map(|resized| termpix::colours_used(resized, options))
+            .collect();
+        colours.sort();
+        colours.dedup();
+        print_legend(&colours);
+    }
+    if args.flag_copy {
+        let mut art = Vec::new();
+        termpix::write_resized(&mut art, &resized_frames[resized_frames.len() - 1], options).unwrap();
+        copy_to_clipboard(out, &art, args.flag_quiet);
+    }
+
+    enter_alt_screen(args);
+    let mut played = 0;
+    loop {
+        for (i, (rendered, delay)) in frames.iter().enumerate() {
+            if played > 0 || i > 0 {
+                out.write_all(rewind.as_bytes()).unwrap();
+            }
+            out.write_all(if played > 0 && i == 0 { &restart } else { rendered }).unwrap();
+            out.flush().unwrap();
+            std::thread::sleep(*delay);
+        }
+
+        played += 1;
+        if loops != 0 && played >= loops {
+            break;
+        }
+        std::thread::sleep(hold);
+    }
+}
+
+/// The cursor moves from the end of a frame back to where it started, to draw over it.
+fn rewind(options: &termpix::RenderOptions, width: u32, height: u32) -> String {
+    let rows = output_rows(options, width, height);
+    match options.region {
+        Some(region) => {
+            let region = region.clip(width, rows);
+            let mut rewind = String::new();
+            if region.height > 1 {
+                rewind += &format!("\x1b[{}A", region.height - 1);
+            }
+            if region.width > 0 {
+                rewind += &format!("\x1b[{}D", region.width);
+            }
+            rewind
+        }
+        // The offset is drawn again with each frame.
+        None => format!("\x1b[{}A", rows + options.offset.1),
+    }
+}
+
+/// Writes the rendered frames of an animation to `path` as an asciinema v2 cast, played
+/// `loops` times, or once if it loops forever. `restart` replaces the first frame on
+/// later loops.
+fn write_cast(path: &str, frames: &[(Vec<u8>, Duration)], restart: &[u8], loops: u32, hold: Duration, rewind: &str, (columns, rows): (u32, u32)) -> io::Result<()> {
+    let mut cast = io::BufWriter::new(std::fs::File::create(path)?);
+    // The extra row holds the cursor after the last row of the image, so nothing scrolls.
+    let header = serde_json::json!({ "version": 2, "width": columns, "height": rows + 1 });
+    writeln!(cast, "{}", header)?;
+
+    let mut time = Duration::default();
+    for played in 0..loops.max(1) {
+        if played > 0 {
+            time += hold;
+        }
+        for (i, (rendered, delay)) in frames.iter().enumerate() {
+            let mut output = String::new();
+            if played > 0 || i > 0 {
+                output += rewind;
+            }
+            let rendered = if played > 0 && i == 0 { restart } else { rendered };
+            // Players don't translate newlines the way a terminal's line discipline does.
+            output += &String::from_utf8_lossy(rendered).replace('\n', "\r\n");
+            writeln!(cast, "{}", serde_json::json!([time.as_secs_f64(), "o", output]))?;
+            time += *delay;
+        }
+    }
+    cast.flush()
+}
+
+/// Parses a frame selection such as `2..5` or `0,3,5`, or a mix of both. Ranges exclude
+/// their end.
+fn get_frames(spec: &str) -> Option<Vec<usize>> {
+    let mut frames = Vec::new();
+    for part in spec.split(',') {
+        let part = part.trim();
+        if let Some(dots) = part.find("..") {
+            let start = part[..dots].parse::<usize>().ok()?;
+            let end = part[dots + 2..].parse::<usize>().ok()?;
+            frames.extend(start..end);
+        } else {
+            frames.push(part.parse().ok()?);
+        }
+    }
+    Some(frames)
+}
+
+/// Parses two grey levels separated by a comma, e.g. `153,102`.
+fn get_shades(spec: &str) -> Option<(u8, u8)> {
+    let mut shades = spec.split(',').map(|shade| shade.trim().parse::<u8>());
+    match (shades.next(), shades.next(), shades.next()) {
+        (Some(Ok(first)), Some(Ok(second)), None) => Some((first, second)),
         _ => None,
     }
 }
 
+/// Rejects combinations of options where one would be silently ignored.
+fn validate(args: &Args) -> Result<(), String> {
+    let conflicts = [
+        ("--fast", args.flag_fast, "--bilinear", args.flag_bilinear),
+        ("--filter", args.flag_filter.is_some(), "--fast", args.flag_fast),
+        ("--filter", args.flag_filter.is_some(), "--bilinear", args.flag_bilinear),
+        ("--text-optimize", args.flag_text_optimize, "--filter", args.flag_filter.is_some()),
+        ("--text-optimize", args.flag_text_optimize, "--fast", args.flag_fast),
+        ("--text-optimize", args.flag_text_optimize, "--bilinear", args.flag_bilinear),
+        ("--no-filter", args.flag_no_filter, "--filter", args.flag_filter.is_some()),
+        ("--no-filter", args.flag_no_filter, "--fast", args.flag_fast),
+        ("--no-filter", args.flag_no_filter, "--bilinear", args.flag_bilinear),
+        ("--no-filter", args.flag_no_filter, "--text-optimize", args.flag_text_optimize),
+        ("--width", args.flag_width.is_some(), "--max-width", args.flag_max_width.is_some()),
+        ("--fit", args.flag_fit.is_some(), "--width", args.flag_width.is_some()),
+        ("--fit", args.flag_fit.is_some(), "--height", args.flag_height.is_some()),
+        ("--fit", args.flag_fit.is_some(), "--geometry", args.flag_geometry.is_some()),
+        ("--fit", args.flag_fit.is_some(), "--keep-aspect", args.flag_keep_aspect.is_some()),
+        ("--geometry", args.flag_geometry.is_some(), "--width", args.flag_width.is_some()),
+        ("--geometry", args.flag_geometry.is_some(), "--height", args.flag_height.is_some()),
+        ("--geometry", args.flag_geometry.is_some(), "--max-width", args.flag_max_width.is_some()),
+        ("--geometry", args.flag_geometry.is_some(), "--max-height", args.flag_max_height.is_some()),
+        ("--geometry", args.flag_geometry.is_some(), "--only-region", args.flag_only_region.is_some()),
+        ("--height", args.flag_height.is_some(), "--max-height", args.flag_max_height.is_some()),
+        ("--inspect", args.flag_inspect.is_some(), "--list", args.flag_list.is_some()),
+        ("--max-colors", args.flag_max_colors.is_some(), "--palette", args.flag_palette.is_some()),
+        ("--inspect", args.flag_inspect.is_some(), "--dry-run", args.flag_dry_run),
+        ("--hash", args.flag_hash.is_some(), "--list", args.flag_list.is_some()),
+        ("--hash", args.flag_hash.is_some(), "--inspect", args.flag_inspect.is_some()),
+        ("--hash", args.flag_hash.is_some(), "--dry-run", args.flag_dry_run),
+        ("--only-region", args.flag_only_region.is_some(), "--list", args.flag_list.is_some()),
+        ("--record", args.flag_record.is_some(), "--list", args.flag_list.is_some()),
+        ("--record", args.flag_record.is_some(), "--stack", args.flag_stack),
+        ("--record", args.flag_record.is_some(), "--dry-run", args.flag_dry_run),
+        ("--copy", args.flag_copy, "--dry-run", args.flag_dry_run),
+        ("--copy", args.flag_copy, "--record", args.flag_record.is_some()),
+        ("--alt-screen", args.flag_alt_screen, "--record", args.flag_record.is_some()),
+        ("--alt-screen", args.flag_alt_screen, "--dry-run", args.flag_dry_run),
+        ("--record", args.flag_record.is_some(), "--iterations", args.flag_iterations.is_some()),
+        ("--test-pattern", args.flag_test_pattern.is_some(), "--list", args.flag_list.is_some()),
+        ("--test-pattern", args.flag_test_pattern.is_some(), "--stack", args.flag_stack),
+        ("--test-pattern", args.flag_test_pattern.is_some(), "--format", args.flag_format.is_some()),
+        ("--crop-aspect", args.flag_crop_aspect.is_some(), "--inspect", args.flag_inspect.is_some()),
+        ("--stack", args.flag_stack, "--height", args.flag_height.is_some()),
+        ("--stack", args.flag_stack, "--max-height", args.flag_max_height.is_some()),
+        ("--stack", args.flag_stack, "--inspect", args.flag_inspect.is_some()),
+        ("--stack", args.flag_stack, "--hash", args.flag_hash.is_some()),
+        ("--stack", args.flag_stack, "--only-region", args.flag_only_region.is_some()),
+        ("--stack", args.flag_stack, "--fit", args.flag_fit.is_some()),
+        ("--stack", args.flag_stack, "--keep-aspect false", args.flag_keep_aspect.as_deref() == Some("false")),
+    ];
+    for &(first, first_given, second, second_given) in conflicts.iter() {
+        if first_given && second_given {
+            return Err(format!("{} and {} can't be used together", first, second));
+        }
+    }
+
+    let list_only = [
+        ("--slideshow-loop", args.flag_slideshow_loop.is_some()),
+        ("--shuffle", args.flag_shuffle),
+        ("--seed", args.flag_seed.is_some()),
+    ];
+    for &(flag, given) in list_only.iter() {
+        if given && args.flag_list.is_none() {
+            return Err(format!("{} only applies to --list", flag));
+        }
+    }
+    match args.flag_unit.as_str() {
+        "cells" | "pixels" => (),
+        unit => return Err(format!("Unknown --unit: {} (expected cells or pixels)", unit)),
+    }
+    match args.flag_fit.as_deref() {
+        None | Some("width") | Some("height") => (),
+        Some(fit) => return Err(format!("Unknown --fit side: {} (expected width or height)", fit)),
+    }
+    let quadrant = matches!(get_mode(args), Ok(termpix::RenderMode::Quadrant));
+    let rgb_escapes = args.flag_true_colour || args.flag_true_color || args.flag_palette.is_some() || args.flag_max_colors.is_some();
+    let requires = [
+        ("--gap", args.flag_gap > 0, "--stack", args.flag_stack),
+        ("--cell-colors", args.flag_cell_colors.is_some(), "--quadrant", quadrant),
+        ("--checker-size", args.flag_checker_size.is_some(), "--checker", args.flag_checker),
+        ("--checker-shades", args.flag_checker_shades.is_some(), "--checker", args.flag_checker),
+        ("--sgr", args.flag_sgr.is_some(), "24-bit colour: --true-colour, --palette or --max-colors", rgb_escapes),
+    ];
+    for &(flag, given, needed, present) in requires.iter() {
+        if given && !present {
+            return Err(format!("{} only applies to {}", flag, needed));
+        }
+    }
+    if let Some(fps) = args.flag_fps {
+        if fps <= 0.0 || !fps.is_finite() {
+            return Err("--fps must be a positive number".to_string());
+        }
+        if Duration::try_from_secs_f32(1.0 / fps).is_err() {
+            return Err(format!("--fps is too low: {}", fps));
+        }
+    }
+    if let Some(secs) = args.flag_deadline {
+        if secs <= 0.0 || secs.is_nan() {
+            return Err("--deadline must be a positive number of seconds".to_string());
+        }
+        if Duration::try_from_secs_f64(secs).is_err() {
+            return Err(format!("--deadline is too long: {} seconds", secs));
+        }
+    }
+    Ok(())
+}
+
+/// The mode chosen with `--mode` or one of the flags for a single mode, which mustn't
+/// disagree.
+fn get_mode(args: &Args) -> Result<termpix::RenderMode, String> {
+    let mut requested = Vec::new();
+    if let Some(ref mode) = args.flag_mode {
+        requested.push((format!("--mode {}", mode), mode.parse()?));
+    }
+    if args.flag_bg_only {
+        requested.push(("--bg-only".to_string(), termpix::RenderMode::BackgroundOnly));
+    }
+    if args.flag_quadrant {
+        requested.push(("--quadrant".to_string(), termpix::RenderMode::Quadrant));
+    }
+
+    let (first_flag, first_mode) = match requested.first() {
+        Some(first) => first,
+        None => return Ok(termpix::RenderMode::HalfBlock),
+    };
+    match requested.iter().find(|(_, mode)| mode != first_mode) {
+        Some((flag, _)) => Err(format!("{} and {} ask for different modes; pick one", first_flag, flag)),
+        None => Ok(*first_mode),
+    }
+}
+
+/// Parses a pixel position such as `10,20`.
+fn get_point(spec: &str) -> Option<(u32, u32)> {
+    let mut coords = spec.split(',').map(|coord| coord.trim().parse::<u32>());
+    match (coords.next(), coords.next(), coords.next()) {
+        (Some(Ok(x)), Some(Ok(y)), None) => Some((x, y)),
+        _ => None,
+    }
+}
+
+/// Parses a cell rectangle such as `4,2,10,5`, as column, row, width and height.
+fn get_region(spec: &str) -> Option<termpix::Region> {
+    let coords: Result<Vec<u32>, _> = spec.split(',').map(|coord| coord.trim().parse()).collect();
+    match coords.ok()?.as_slice() {
+        &[x, y, width, height] if width > 0 && height > 0 => Some(termpix::Region { x, y, width, height }),
+        _ => None,
+    }
+}
+
+/// Parses an aspect ratio such as `16:9`.
+fn get_aspect(spec: &str) -> Option<(u32, u32)> {
+    let mut sides = spec.split(':').map(|side| side.trim().parse::<u32>());
+    match (sides.next(), sides.next(), sides.next()) {
+        (Some(Ok(width)), Some(Ok(height)), None) if width > 0 && height > 0 => Some((width, height)),
+        _ => None,
+    }
+}
+
+/// A `--geometry` value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Geometry {
+    width: Option<u32>,
+    height: Option<u32>,
+    offset: (u32, u32),
+}
+
+/// Parses an ImageMagick-style size such as `80x40`, `100x`, `x50` or `80x40+10+2`. A bare
+/// number is a width.
+fn parse_geometry(spec: &str) -> Option<Geometry> {
+    let parts: Vec<_> = spec.split('+').collect();
+    let (size, offset) = match *parts.as_slice() {
+        [size] => (size, (0, 0)),
+        [size, x, y] => (size, (x.parse().ok()?, y.parse().ok()?)),
+        _ => return None,
+    };
+    let dimension = |d: &str| if d.is_empty() {
+        Some(None)
+    } else {
+        d.parse().ok().filter(|&d| d > 0).map(Some)
+    };
+    let (width, height) = match size.find('x') {
+        Some(x) => (dimension(&size[..x])?, dimension(&size[x + 1..])?),
+        None => (dimension(size)?, None),
+    };
+    if width.is_none() && height.is_none() {
+        return None;
+    }
+    Some(Geometry { width, height, offset })
+}
+
+/// Parses a `--slideshow-loop` count, with `forever` as 0.
+fn get_slideshow_loops(spec: &str) -> Option<u32> {
+    match spec {
+        "forever" => Some(0),
+        _ => spec.parse().ok().filter(|&loops| loops > 0),
+    }
+}
+
+/// Whether a slideshow of `loops` passes, 0 meaning forever, goes round again after the
+/// `played`th. `None` if that pass drew nothing, however many are left: going round again
+/// would only fail the same way, as fast as it can.
+fn another_pass(drawn: bool, played: u32, loops: u32) -> Option<bool> {
+    if !drawn {
+        return None;
+    }
+    Some(loops == 0 || played < loops)
+}
+
+fn read_list(path: &str) -> std::io::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
 fn main() {
+    #[cfg(feature = "logging")]
+    logging::init();
+
     let args: Args = Docopt::new(USAGE)
         .and_then(|d| d.deserialize())
-        .unwrap_or_else(|e| e.exit());
+        .unwrap_or_else(|e| {
+            if e.fatal() {
+                eprintln!("{}", e);
+                std::process::exit(EXIT_USAGE)
+            }
+            e.exit()
+        });
+
+    or_usage_exit(validate(&args));
+
+    let (wait, quiet) = (args.flag_wait, args.flag_quiet);
+    let deadline = match args.flag_deadline {
+        // `validate` has checked that this fits.
+        Some(secs) => Duration::from_secs_f64(secs),
+        None => {
+            run(args);
+            if wait {
+                wait_for_key(quiet);
+            }
+            return;
+        }
+    };
+    let (finished, done) = mpsc::channel();
+    std::thread::spawn(move || {
+        run(args);
+        finished.send(()).unwrap();
+    });
+    match done.recv_timeout(deadline) {
+        Ok(()) if wait => wait_for_key(quiet),
+        Ok(()) => (),
+        Err(RecvTimeoutError::Timeout) => {
+            eprintln!("Gave up after the --deadline of {:.1?}", deadline);
+            std::process::exit(EXIT_DEADLINE)
+        }
+        // The worker panicked, and has already said why.
+        Err(RecvTimeoutError::Disconnected) => std::process::exit(101),
+    }
+}
 
+/// The value of an option that parsed, or exits with the usage status after printing why
+/// it didn't.
+fn or_usage_exit<T, E: std::fmt::Display>(parsed: Result<T, E>) -> T {
+    parsed.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(EXIT_USAGE)
+    })
+}
 
-    let filter = (&args.flag_filter)
-        .as_ref()
-        .map_or(imageops::Gaussian, |f| {
-        get_filter(f.clone()).unwrap_or_else(|| {
-            eprintln!("Unknow filter: {}",f);
-            std::process::exit(-1)
-        })
+fn run(mut args: Args) {
+    let geometry = args.flag_geometry.as_ref().map(|g| {
+        or_usage_exit(parse_geometry(g).ok_or_else(|| {
+            format!("Invalid --geometry value: {} (expected e.g. 80x40, 80x, x40 or 80x40+10+2)", g)
+        }))
     });
+    if let Some(geometry) = geometry {
+        args.flag_width = geometry.width;
+        args.flag_height = geometry.height;
+    }
+
+    if let Some(ref pattern) = args.flag_test_pattern {
+        or_usage_exit(pattern.parse::<termpix::TestPattern>());
+        // Stands in for the file name in messages and --json.
+        args.arg_file = pattern.clone();
+    }
+    if let Some(ref ratio) = args.flag_crop_aspect {
+        or_usage_exit(get_aspect(ratio).ok_or_else(|| {
+            format!("Invalid --crop-aspect value: {} (expected width:height, e.g. 1:1 or 16:9)", ratio)
+        }));
+    }
+
+    let filter = args.flag_filter.as_ref().map(|f| or_usage_exit(f.parse())).unwrap_or_default();
+
+    let keep_aspect = args.flag_keep_aspect.as_ref().map(|k| {
+        or_usage_exit(k.parse::<bool>().map_err(|_| format!("Invalid --keep-aspect value: {} (expected true or false)", k)))
+    });
+
+    let frames = args.flag_frames.as_ref().map(|f| {
+        or_usage_exit(get_frames(f).ok_or_else(|| format!("Invalid --frames value: {} (expected e.g. 2..5 or 0,3,5)", f)))
+    });
+
+    let channel = args.flag_channel.as_ref().map(|c| or_usage_exit(c.parse()));
+
+    if let Some(ref name) = args.flag_format {
+        or_usage_exit(get_format(name).ok_or_else(|| {
+            format!("Unknown format: {} (expected e.g. png, jpeg, gif, webp, bmp, tga or svg)", name)
+        }));
+    }
+
+    let simulate = args.flag_simulate.as_ref().map(|d| or_usage_exit(d.parse()));
+
+    let mode = or_usage_exit(get_mode(&args));
+    if args.flag_unit == "pixels" {
+        // Each cell shows a fixed number of the image's pixels, so this is exact apart from
+        // rounding down.
+        let to_cells = |pixels: u32, per_cell: u32| (pixels / per_cell).max(1);
+        args.flag_width = args.flag_width.map(|width| to_cells(width, mode.pixels_per_column()));
+        args.flag_max_width = args.flag_max_width.map(|width| to_cells(width, mode.pixels_per_column()));
+        args.flag_height = args.flag_height.map(|height| to_cells(height, mode.pixels_per_row()));
+        args.flag_max_height = args.flag_max_height.map(|height| to_cells(height, mode.pixels_per_row()));
+    }
+
+    let inspect = args.flag_inspect.as_ref().map(|p| {
+        or_usage_exit(get_point(p).ok_or_else(|| format!("Invalid --inspect value: {} (expected a pixel position, e.g. 10,20)", p)))
+    });
+
+    let cell_colours = args.flag_cell_colors.as_ref().map(|method| or_usage_exit(method.parse())).unwrap_or_default();
+
+    let sgr = args.flag_sgr.as_ref().map(|syntax| or_usage_exit(syntax.parse())).unwrap_or_default();
+
+    let luma = args.flag_luma.as_ref().map(|l| or_usage_exit(l.parse())).unwrap_or_default();
+
+    let hash: Option<termpix::HashKind> = args.flag_hash.as_ref().map(|h| or_usage_exit(h.parse()));
+
+    let region = args.flag_only_region.as_ref().map(|r| {
+        or_usage_exit(get_region(r).ok_or_else(|| {
+            format!("Invalid --only-region value: {} (expected x,y,width,height in cells, e.g. 4,2,10,5)", r)
+        }))
+    });
+
+    if args.flag_iterations == Some(0) {
+        eprintln!("--iterations must be at least 1");
+        std::process::exit(EXIT_USAGE)
+    }
+
+    if args.flag_max_colors == Some(0) {
+        eprintln!("--max-colors must be at least 1");
+        std::process::exit(EXIT_USAGE)
+    }
+
+    if args.flag_pixel_aspect.is_some_and(|ratio| ratio <= 0.0 || !ratio.is_finite()) {
+        eprintln!("--pixel-aspect must be a positive number");
+        std::process::exit(EXIT_USAGE)
+    }
+
+    if (args.flag_true_colour || args.flag_true_color) && !args.flag_quiet && !terminal_has_true_colour() {
+        eprintln!("Warning: COLORTERM doesn't report 24-bit colour support; if the colours look wrong, try without --true-colour.");
+    }
+
+    let palette = args.flag_palette.as_ref().map(|path| {
+        let contents = std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Could not read palette {}: {}", path, e);
+            std::process::exit(EXIT_NOT_FOUND)
+        });
+        or_usage_exit(termpix::parse_palette(&contents).map_err(|e| format!("Invalid palette {}: {}", path, e)))
+    });
+
+    let theme = match args.flag_theme.as_str() {
+        "auto" => terminal_theme().unwrap_or_default(),
+        theme => or_usage_exit(theme.parse().map_err(|_| format!("Unknown theme: {} (expected dark, light or auto)", theme))),
+    };
+
+    let checker = if args.flag_checker {
+        let spec = args.flag_checker_shades.as_deref().unwrap_or("153,102");
+        let shades = or_usage_exit(get_shades(spec).ok_or_else(|| {
+            format!("Invalid --checker-shades value: {} (expected two grey levels, e.g. 153,102)", spec)
+        }));
+        Some(termpix::Checkerboard { size: args.flag_checker_size.unwrap_or(4).max(1), shades })
+    } else {
+        None
+    };
+
+    let options = termpix::RenderOptions {
+        true_colour: args.flag_true_colour || args.flag_true_color,
+        sgr,
+        mode,
+        cell_colours,
+        filter,
+        fast: args.flag_fast,
+        bilinear: args.flag_bilinear,
+        text_optimize: args.flag_text_optimize,
+        channel,
+        luma,
+        simulate,
+        pad_even: args.flag_pad_even,
+        palette,
+        max_colours: args.flag_max_colors,
+        vblend: args.flag_vblend,
+        checker,
+        theme,
+        reset: !args.flag_no_reset,
+        region,
+        sparse: args.flag_sparse,
+        flush_rows: args.flag_flush_rows,
+        offset: geometry.map_or((0, 0), |geometry| geometry.offset),
+    };
+
+    debug!("drawing in {} mode on a {} background{}", options.mode, options.theme,
+           match (args.flag_theme.as_str(), terminal_theme()) {
+               ("auto", Some(_)) => ", going by COLORFGBG",
+               ("auto", None) => ", as COLORFGBG doesn't say",
+               _ => "",
+           });
+    if options.palette.is_some() {
+        debug!("using the colours from --palette");
+    } else if let Some(max_colours) = options.max_colours {
+        debug!("picking {} colours for each image, for --max-colors", max_colours);
+    } else if options.true_colour {
+        debug!("using 24-bit colour, for --true-colour");
+    } else {
+        debug!("using the 256-colour palette: no --true-colour{}",
+               if terminal_has_true_colour() { ", though COLORTERM reports support for it" } else { "" });
+    }
+
+    let mut out = open_output(&args);
+
+    let slideshow_loops = match args.flag_slideshow_loop {
+        Some(ref spec) => or_usage_exit(get_slideshow_loops(spec).ok_or_else(|| {
+            format!("Invalid slideshow loop count: {} (expected a number or forever)", spec)
+        })),
+        None => 1,
+    };
+
+    if let Some(ref list) = args.flag_list {
+        let paths = read_list(list).unwrap_or_else(|e| {
+            eprintln!("Could not read {}: {}", list, e);
+            std::process::exit(EXIT_NOT_FOUND)
+        });
+        let mut paths = paths;
+        let mut rng = match args.flag_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut played = 0;
+        loop {
+            if args.flag_shuffle {
+                paths.shuffle(&mut rng);
+            }
+            let mut drawn = false;
+            for path in &paths {
+                match load_image(&args, path) {
+                    Ok((img, decode_time)) => {
+                        render(&args, path, img, decode_time, &options, keep_aspect, &mut *out);
+                        drawn = true;
+                    }
+                    Err(e) => eprintln!("Skipping {}: {}", path, e),
+                }
+            }
+
+            played += 1;
+            match another_pass(drawn, played, slideshow_loops) {
+                Some(true) => (),
+                Some(false) => break,
+                None => {
+                    eprintln!("None of the images in {} could be drawn; giving up", list);
+                    std::process::exit(EXIT_DECODE)
+                }
+            }
+        }
+        return;
+    }
 
-    let img = get_image(&args.arg_file).unwrap_or_else(|e| {
-        eprint!("{}", e);
-        std::process::exit(-1)
+    if args.flag_stack {
+        // Every image gets the same width, and as many rows as its aspect ratio needs.
+        let stack_args = Args {
+            flag_width: Some(args.flag_width.unwrap_or_else(|| max_or(terminal_dimensions().0, args.flag_max_width))),
+            ..args.clone()
+        };
+        let mut drawn = false;
+        for path in &args.arg_files {
+            match load_image(&args, path) {
+                Ok((img, decode_time)) => {
+                    if drawn && !args.flag_dry_run {
+                        for _ in 0..args.flag_gap {
+                            writeln!(out).unwrap();
+                        }
+                    }
+                    render(&stack_args, path, img, decode_time, &options, keep_aspect, &mut *out);
+                    drawn = true;
+                }
+                Err(e) => eprintln!("Skipping {}: {}", path, e),
+            }
+        }
+        return;
+    }
+
+    if let Some((x, y)) = inspect {
+        let (img, _) = load_image(&args, &args.arg_file).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code())
+        });
+        let (width, height) = img.dimensions();
+        if x >= width || y >= height {
+            eprintln!("Pixel {},{} is outside the {}x{} image", x, y, width, height);
+            std::process::exit(EXIT_USAGE)
+        }
+        let Rgba([r, g, b, a]) = img.get_pixel(x, y);
+        println!("rgba({}, {}, {}, {}) #{:02x}{:02x}{:02x}{:02x}", r, g, b, a, r, g, b, a);
+        return;
+    }
+
+    if let Some(kind) = hash {
+        let (img, _) = load_image(&args, &args.arg_file).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code())
+        });
+        println!("{:016x}", termpix::image_hash(&img, kind, luma));
+        return;
+    }
+
+    // Dry runs and benchmarks only need the first frame.
+    if !args.flag_dry_run && args.flag_iterations.is_none() {
+        let start = Instant::now();
+        let format = args.flag_format.as_ref().and_then(|name| get_format(name));
+        let animation = get_animation(&args.arg_file, format).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code())
+        });
+        if let Some(mut animation) = animation {
+            if let Some(ref frames) = frames {
+                for &frame in frames.iter().filter(|&&frame| frame >= animation.frames.len()) {
+                    if !args.flag_quiet {
+                        eprintln!("Warning: skipping frame {}, the animation only has {}", frame, animation.frames.len());
+                    }
+                }
+                animation.frames = animation.frames.into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| frames.contains(i))
+                    .map(|(_, frame)| frame)
+                    .collect();
+                if animation.frames.is_empty() {
+                    eprintln!("None of the frames given to --frames exist");
+                    std::process::exit(EXIT_USAGE)
+                }
+            }
+            play_animation(&args, &animation, start.elapsed(), &options, keep_aspect, &mut *out);
+            return;
+        }
+    }
+
+    let (img, decode_time) = load_image(&args, &args.arg_file).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(e.exit_code())
     });
+    if args.flag_record.is_some() {
+        eprintln!("--record only applies to animations, and {} isn't one", args.arg_file);
+        std::process::exit(EXIT_USAGE)
+    }
+    render(&args, &args.arg_file, img, decode_time, &options, keep_aspect, &mut *out);
+}
+
+/// The theme COLORFGBG suggests, if it's set. Its last field is the background's index in
+/// the 16-colour palette.
+fn terminal_theme() -> Option<termpix::Theme> {
+    let colorfgbg = std::env::var("COLORFGBG").ok()?;
+    match colorfgbg.rsplit(';').next()?.parse::<u8>().ok()? {
+        0..=6 | 8 => Some(termpix::Theme::Dark),
+        7 | 9..=15 => Some(termpix::Theme::Light),
+        _ => None,
+    }
+}
+
+fn terminal_has_true_colour() -> bool {
+    match std::env::var("COLORTERM") {
+        Ok(colorterm) => colorterm == "truecolor" || colorterm == "24bit",
+        Err(_) => false,
+    }
+}
+
+/// Loads the image at `path`, along with how long decoding it took.
+fn load_image(args: &Args, path: &String) -> std::result::Result<(DynamicImage, Duration), LoadImageError> {
+    let start = Instant::now();
+    if let Some(ref pattern) = args.flag_test_pattern {
+        let pattern = pattern.parse().expect("checked in run");
+        return Ok((crop_aspect(args, termpix::test_pattern(pattern)), start.elapsed()));
+    }
+    let format = args.flag_format.as_ref().and_then(|name| get_format(name));
+    let img = get_image(path, args.flag_svg_max_pixels, format)?;
+    let decode_time = start.elapsed();
+    debug!("decoded {}: {}x{} {:?} in {:.1?}", path, img.width(), img.height(), img.color(), decode_time);
+    if args.flag_verbose {
+        eprintln!("termpix: decoded {} in {:.1?}", path, decode_time);
+    }
+    Ok((crop_aspect(args, img), decode_time))
+}
+
+/// `img` cropped as --crop-aspect asks, if it was given.
+fn crop_aspect(args: &Args, img: DynamicImage) -> DynamicImage {
+    match args.flag_crop_aspect {
+        Some(ref ratio) => {
+            let (width, height) = get_aspect(ratio).expect("checked in run");
+            termpix::crop_to_aspect(&img, width, height)
+        }
+        None => img,
+    }
+}
+
+/// What `--json` reports about each image drawn.
+#[derive(Serialize)]
+struct Stats<'a> {
+    file: &'a str,
+    source: Size,
+    cells: Size,
+    pixels: Size,
+    mode: String,
+    colour: &'static str,
+    frames: usize,
+    decode_ms: f64,
+    resize_ms: Option<f64>,
+    render_ms: Option<f64>,
+    bytes: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Size {
+    width: u32,
+    height: u32,
+}
+
+impl<'a> Stats<'a> {
+    fn new(file: &'a str, source: (u32, u32), options: &termpix::RenderOptions, width: u32, height: u32, decode_time: Duration) -> Self {
+        let (pixel_width, pixel_height) = termpix::resized_dimensions(options, width, height);
+        Stats {
+            file,
+            source: Size { width: source.0, height: source.1 },
+            cells: Size { width, height: output_rows(options, width, height) },
+            pixels: Size { width: pixel_width, height: pixel_height },
+            mode: options.mode.to_string(),
+            colour: if options.palette.is_some() {
+                "palette"
+            } else if options.max_colours.is_some() {
+                "max-colors"
+            } else if options.true_colour {
+                "true-colour"
+            } else {
+                "256"
+            },
+            frames: 1,
+            decode_ms: millis(decode_time),
+            resize_ms: None,
+            render_ms: None,
+            bytes: None,
+        }
+    }
+
+    fn print(&self) {
+        eprintln!("{}", serde_json::to_string(self).unwrap());
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Where to draw images: stdout, or the file descriptor given with `--fd`.
+fn open_output(args: &Args) -> Box<dyn Write> {
+    match args.flag_fd {
+        Some(fd) => open_fd(fd),
+        None => Box::new(io::stdout().lock()),
+    }
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Box<dyn Write> {
+    use std::os::unix::io::FromRawFd;
+
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        eprintln!("File descriptor {} isn't open", fd);
+        std::process::exit(EXIT_USAGE)
+    }
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        eprintln!("File descriptor {} isn't open for writing", fd);
+        std::process::exit(EXIT_USAGE)
+    }
+    // Leaked, so that the descriptor stays open until termpix exits: nothing else can reuse
+    // it first, and --alt-screen can still switch back on it at exit.
+    let file: &'static mut std::fs::File = Box::leak(Box::new(unsafe { std::fs::File::from_raw_fd(fd) }));
+    Box::new(file)
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Box<dyn Write> {
+    eprintln!("--fd is only supported on Unix");
+    std::process::exit(EXIT_USAGE)
+}
+
+/// With --alt-screen, switches to the alternate screen. Called just before drawing, so
+/// that errors before then stay on the normal screen where they can be read.
+#[cfg(unix)]
+fn enter_alt_screen(args: &Args) {
+    if args.flag_alt_screen {
+        screen::enter(args.flag_fd.unwrap_or(libc::STDOUT_FILENO));
+    }
+}
+
+#[cfg(not(unix))]
+fn enter_alt_screen(args: &Args) {
+    if args.flag_alt_screen {
+        eprintln!("--alt-screen is only supported on Unix");
+        std::process::exit(EXIT_USAGE)
+    }
+}
+
+#[cfg(unix)]
+fn wait_for_key(quiet: bool) {
+    if let Err(e) = screen::wait_for_key() {
+        if !quiet {
+            eprintln!("Warning: couldn't wait for a key press: {}", e);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_for_key(_quiet: bool) {
+    eprintln!("--wait is only supported on Unix");
+    std::process::exit(EXIT_USAGE)
+}
+
+/// Passes writes through to `inner`, counting the bytes written.
+struct ByteCounter<W> {
+    inner: W,
+    count: usize,
+}
+
+impl<W: Write> Write for ByteCounter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn render(args: &Args, path: &str, img: DynamicImage, decode_time: Duration, options: &termpix::RenderOptions, keep_aspect: Option<bool>, out: &mut dyn Write) {
     let (orig_width, orig_height) = img.dimensions();
-    let true_colour = args.flag_true_colour || args.flag_true_color;
-    let (width, height) = determine_size(args, orig_width, orig_height);
+    let pixels_per_row = options.mode.pixels_per_row();
+    let (width, height) = determine_size(args, keep_aspect, pixels_per_row, orig_width, orig_height);
+    check_no_filter(args, options, orig_width, orig_height, width, height);
+    let columns = visible_columns(args, width);
+    debug!("fitting {}x{} pixels into {}x{} cells", orig_width, orig_height, columns, output_rows(options, width, height));
+    let mut stats = Stats::new(path, (orig_width, orig_height), options, columns, height, decode_time);
 
-    termpix::print_image(img, true_colour, width, height, filter);
+    if args.flag_dry_run {
+        if args.flag_json {
+            stats.print();
+        } else {
+            println!("{}x{} pixels, {}x{} cells", stats.pixels.width, stats.pixels.height, columns, stats.cells.height);
+        }
+        return;
+    }
+
+    if let Some(iterations) = args.flag_iterations {
+        benchmark(&img, options, width, height, iterations);
+        return;
+    }
+    enter_alt_screen(args);
+
+    let start = Instant::now();
+    let resized = clip_columns(termpix::resize_image(&img, options, width, height), options, columns);
+    let resize_time = start.elapsed();
+
+    let start = Instant::now();
+    // Buffered, so that rows only reach the terminal when --flush-rows says so.
+    let mut out = ByteCounter { inner: io::BufWriter::new(out), count: 0 };
+    termpix::write_resized(&mut out, &resized, options).unwrap();
+    let render_time = start.elapsed();
+
+    if args.flag_verbose {
+        eprintln!("termpix: resized in {:.1?}, rendered in {:.1?}", resize_time, render_time);
+        eprintln!("termpix: {}x{} cells, {} bytes", columns, stats.cells.height, out.count);
+    }
+    if args.flag_json {
+        stats.resize_ms = Some(millis(resize_time));
+        stats.render_ms = Some(millis(render_time));
+        stats.bytes = Some(out.count);
+        stats.print();
+    }
+    if args.flag_legend {
+        print_legend(&termpix::colours_used(&resized, options));
+    }
+    if args.flag_copy {
+        let mut art = Vec::new();
+        termpix::write_resized(&mut art, &resized, options).unwrap();
+        copy_to_clipboard(&mut out.inner, &art, args.flag_quiet);
+    }
 }
 
-fn determine_size(args: Args, orig_width: u32, orig_height: u32) -> (u32, u32) {
-    match (args.flag_width, args.flag_height) {
-        (Some(w), Some(h)) => (w, h * 2),
-        (Some(w), None) => (w, scale_dimension(w, orig_height, orig_width)),
-        (None, Some(h)) => (scale_dimension(h * 2, orig_width, orig_height), h * 2),
-        (None, None) => {
-            let size = terminal_size();
+/// The most base64 to send in one OSC 52 sequence. Terminals cap it differently, and this
+/// is about the smallest common limit.
+const OSC_52_LIMIT: usize = 100_000;
 
-            if let Some((Width(terminal_width), Height(terminal_height))) = size {
+/// Asks the terminal to put `art` on the system clipboard, unless it's too big to be sure
+/// the terminal would take it.
+fn copy_to_clipboard(out: &mut dyn Write, art: &[u8], quiet: bool) {
+    let encoded = base64(art);
+    if encoded.len() > OSC_52_LIMIT {
+        if !quiet {
+            eprintln!("Warning: not copying, as the image is {} bytes once encoded and terminals only reliably accept {}; \
+                       try a smaller --width", encoded.len(), OSC_52_LIMIT);
+        }
+        return;
+    }
+    write!(out, "\x1b]52;c;{}\x07", encoded).unwrap();
+    out.flush().unwrap();
+}
+
+/// Standard, padded base64.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Prints a swatch, hex value and palette index for each colour to stderr.
+fn print_legend(colours: &[termpix::UsedColour]) {
+    eprintln!("termpix: {} colours used", colours.len());
+    for colour in colours {
+        let [r, g, b] = colour.rgb;
+        let hex = format!("#{:02x}{:02x}{:02x}", r, g, b);
+        match colour.index {
+            Some(index) => eprintln!("\x1b[48;5;{}m    \x1b[m {} {}", index, hex, index),
+            None => eprintln!("\x1b[48;2;{};{};{}m    \x1b[m {}", r, g, b, hex),
+        }
+    }
+}
+
+/// Resizes and draws `img` `iterations` times into a sink, printing the average times.
+fn benchmark(img: &DynamicImage, options: &termpix::RenderOptions, width: u32, height: u32, iterations: u32) {
+    let (mut resize_time, mut render_time) = (Duration::default(), Duration::default());
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let resized = termpix::resize_image(img, options, width, height);
+        resize_time += start.elapsed();
+
+        let start = Instant::now();
+        termpix::write_resized(&mut io::sink(), &resized, options).unwrap();
+        render_time += start.elapsed();
+    }
+    eprintln!("termpix: {} iterations, resized in {:.1?} and rendered in {:.1?} on average",
+              iterations, resize_time / iterations, render_time / iterations);
+}
+
+/// Writes `done` out of `total` over the last progress line on stderr, finishing the line
+/// once everything's done.
+fn show_progress(done: usize, total: usize) {
+    eprint!("\rtermpix: rendered {}/{} frames", done, total);
+    if done == total {
+        eprintln!();
+    }
+}
+
+/// How many of the `width` columns of the image fit. With --fit height, anything past the
+/// terminal's width is cut off, as the terminal would otherwise wrap every row.
+fn visible_columns(args: &Args, width: u32) -> u32 {
+    match args.flag_fit.as_deref() {
+        Some("height") => min(width, max_or(terminal_dimensions().0, args.flag_max_width)),
+        _ => width,
+    }
+}
+
+/// `img`, already sized by `resize_image`, with only its first `columns` columns of cells.
+fn clip_columns(img: image::RgbaImage, options: &termpix::RenderOptions, columns: u32) -> image::RgbaImage {
+    let pixels = columns * options.mode.pixels_per_column();
+    if img.width() <= pixels {
+        img
+    } else {
+        imageops::crop_imm(&img, 0, 0, pixels, img.height()).to_image()
+    }
+}
+
+/// With --no-filter, exits unless the image is already the size it would be resized to.
+fn check_no_filter(args: &Args, options: &termpix::RenderOptions, orig_width: u32, orig_height: u32, width: u32, height: u32) {
+    let (scaled_width, scaled_height) = termpix::scaled_dimensions(options, width, height);
+    if args.flag_no_filter && (scaled_width, scaled_height) != (orig_width, orig_height) {
+        eprintln!("--no-filter needs a {}x{} pixel image to fit the output, but this one is {}x{}",
+                  scaled_width, scaled_height, orig_width, orig_height);
+        std::process::exit(EXIT_USAGE);
+    }
+}
+
+/// How many rows of cells drawing at this size takes, including any padding.
+fn output_rows(options: &termpix::RenderOptions, width: u32, height: u32) -> u32 {
+    termpix::resized_dimensions(options, width, height).1 / options.mode.pixels_per_row()
+}
+
+/// The output size in columns and pixel rows, for a mode that draws `pixels_per_row` rows
+/// of pixels in each row of cells.
+fn determine_size(args: &Args, keep_aspect: Option<bool>, pixels_per_row: u32, orig_width: u32, orig_height: u32) -> (u32, u32) {
+    // Stretching the width here has the same effect as resizing the image first, without
+    // resampling it twice.
+    let orig_width = match args.flag_pixel_aspect {
+        Some(ratio) => ((orig_width as f32 * ratio).round() as u32).max(1),
+        None => orig_width,
+    };
+    // Fitting one side is the same as giving it explicitly, at the terminal's size.
+    let (width, height) = match args.flag_fit.as_deref() {
+        Some("width") => (Some(max_or(terminal_dimensions().0, args.flag_max_width)), None),
+        Some("height") => (None, Some(max_or(terminal_dimensions().1, args.flag_max_height))),
+        _ => (args.flag_width, args.flag_height),
+    };
+    match (width, height) {
+        (Some(w), Some(h)) => {
+            if keep_aspect == Some(true) {
+                fit_to_size(orig_width, orig_height, w, h, None, None, pixels_per_row)
+            } else {
+                (w, h * pixels_per_row)
+            }
+        }
+        (Some(w), None) => {
+            if keep_aspect == Some(false) {
+                let (_, terminal_height) = terminal_dimensions();
+                (w, pixels_per_row * max_or(terminal_height, args.flag_max_height))
+            } else {
+                (w, scale_dimension(w * pixels_per_row, orig_height, orig_width * 2).max(pixels_per_row))
+            }
+        }
+        (None, Some(h)) => {
+            if keep_aspect == Some(false) {
+                let (terminal_width, _) = terminal_dimensions();
+                (max_or(terminal_width, args.flag_max_width), h * pixels_per_row)
+            } else {
+                (scale_dimension(h * 2, orig_width, orig_height).max(1), h * pixels_per_row)
+            }
+        }
+        (None, None) => {
+            let (terminal_width, terminal_height) = terminal_dimensions();
+            if keep_aspect == Some(false) {
+                (
+                    max_or(terminal_width, args.flag_max_width),
+                    pixels_per_row * max_or(terminal_height, args.flag_max_height),
+                )
+            } else {
                 fit_to_size(
                     orig_width,
                     orig_height,
-                    terminal_width as u32,
-                    (terminal_height - 1) as u32,
+                    terminal_width,
+                    terminal_height,
                     args.flag_max_width,
                     args.flag_max_height,
+                    pixels_per_row,
                 )
-            } else {
-                writeln!(std::io::stderr(), "Neither --width or --height specified, and could not determine terminal size. Giving up.").unwrap();
-                std::process::exit(1);
             }
         }
     }
 }
 
+/// Columns and rows available for the image: `TERMPIX_COLS`/`TERMPIX_ROWS` if set, for
+/// previewers that run termpix without a terminal, otherwise the terminal size leaving a
+/// row for the prompt.
+fn terminal_dimensions() -> (u32, u32) {
+    let (env_cols, env_rows) = (size_from_env("TERMPIX_COLS"), size_from_env("TERMPIX_ROWS"));
+    if let (Some(cols), Some(rows)) = (env_cols, env_rows) {
+        debug!("using TERMPIX_COLS and TERMPIX_ROWS as the terminal size: {}x{}", cols, rows);
+        return (cols, rows);
+    }
+
+    if let Some((Width(terminal_width), Height(terminal_height))) = terminal_size() {
+        debug!("the terminal is {}x{}, leaving a row for the prompt", terminal_width, terminal_height);
+        // Even a one-row terminal gets a one-row image, overwriting the prompt line.
+        (
+            env_cols.unwrap_or(max(terminal_width, 1) as u32),
+            env_rows.unwrap_or(max(terminal_height.saturating_sub(1), 1) as u32),
+        )
+    } else {
+        eprintln!("Could not determine terminal size; specify both --width and --height, or set TERMPIX_COLS and TERMPIX_ROWS. Giving up.");
+        std::process::exit(EXIT_USAGE);
+    }
+}
+
+fn size_from_env(name: &str) -> Option<u32> {
+    let value = std::env::var(name).ok()?;
+    match value.trim().parse() {
+        Ok(size) if size > 0 => Some(size),
+        _ => {
+            eprintln!("Invalid {}: {} (expected a positive number)", name, value);
+            std::process::exit(EXIT_USAGE);
+        }
+    }
+}
+
+fn max_or(available: u32, max: Option<u32>) -> u32 {
+    match max {
+        Some(max) => min(max, available),
+        None => available,
+    }
+}
+
 fn scale_dimension(other: u32, orig_this: u32, orig_other: u32) -> u32 {
     (orig_this as f32 * other as f32 / orig_other as f32 + 0.5) as u32
 }
 
+/// Fits the image inside the terminal, in columns and pixel rows. Cells are about twice as
+/// tall as they are wide, so with `pixels_per_row` of 2 the pixels are square.
 pub fn fit_to_size(
     orig_width: u32,
     orig_height: u32,
@@ -169,25 +1699,250 @@ pub fn fit_to_size(
     terminal_height: u32,
     max_width: Option<u32>,
     max_height: Option<u32>,
+    pixels_per_row: u32,
 ) -> (u32, u32) {
     let target_width = match max_width {
         Some(max_width) => min(max_width, terminal_width),
         None => terminal_width,
     };
 
-    //2 pixels per terminal row
-    let target_height = 2 * match max_height {
+    let target_rows = match max_height {
         Some(max_height) => min(max_height, terminal_height),
         None => terminal_height,
     };
 
-    let calculated_width = scale_dimension(target_height, orig_width, orig_height);
-    if calculated_width <= target_width {
-        (calculated_width, target_height)
+    let calculated_width = scale_dimension(target_rows * 2, orig_width, orig_height);
+    let (width, height) = if calculated_width <= target_width {
+        (calculated_width, target_rows * pixels_per_row)
     } else {
         (
             target_width,
-            scale_dimension(target_width, orig_height, orig_width),
+            scale_dimension(target_width * pixels_per_row, orig_height, orig_width * 2),
         )
+    };
+    // Very wide or tall images can round down to nothing in a small space; show at least
+    // a one-cell sliver instead.
+    (max(width, 1), max(height, pixels_per_row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(argv: &[&str]) -> Args {
+        Docopt::new(USAGE)
+            .and_then(|d| d.argv(["termpix"].iter().chain(argv)).deserialize())
+            .unwrap()
+    }
+
+    fn geometry(width: Option<u32>, height: Option<u32>, offset: (u32, u32)) -> Option<Geometry> {
+        Some(Geometry { width, height, offset })
+    }
+
+    #[test]
+    fn parse_geometry_sizes() {
+        assert_eq!(parse_geometry("80x40"), geometry(Some(80), Some(40), (0, 0)));
+        assert_eq!(parse_geometry("100x"), geometry(Some(100), None, (0, 0)));
+        assert_eq!(parse_geometry("x50"), geometry(None, Some(50), (0, 0)));
+        assert_eq!(parse_geometry("60"), geometry(Some(60), None, (0, 0)));
+    }
+
+    #[test]
+    fn parse_geometry_offsets() {
+        assert_eq!(parse_geometry("80x40+10+2"), geometry(Some(80), Some(40), (10, 2)));
+        assert_eq!(parse_geometry("100x+0+5"), geometry(Some(100), None, (0, 5)));
+        assert_eq!(parse_geometry("x50+3+0"), geometry(None, Some(50), (3, 0)));
+    }
+
+    #[test]
+    fn parse_geometry_rejects() {
+        for spec in &["0x0", "0x40", "80x0", "x", "", "+1", "+1+2", "80x40+1", "80x40+1+2+3", "80x40+a+2", "axb"] {
+            assert_eq!(parse_geometry(spec), None, "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_conflicting_options() {
+        let conflicts: &[(&[&str], &str)] = &[
+            (&["--fast", "--bilinear"], "--fast and --bilinear can't be used together"),
+            (&["--filter", "nearest", "--fast"], "--filter and --fast can't be used together"),
+            (&["--no-filter", "--text-optimize"], "--no-filter and --text-optimize can't be used together"),
+            (&["--width", "10", "--max-width", "20"], "--width and --max-width can't be used together"),
+            (&["--fit", "width", "--geometry", "10x"], "--fit and --geometry can't be used together"),
+            (&["--geometry", "10x5", "--height", "5"], "--geometry and --height can't be used together"),
+            (&["--hash", "out.txt", "--dry-run"], "--hash and --dry-run can't be used together"),
+            (&["--copy", "--record", "out.cast"], "--copy and --record can't be used together"),
+            (&["--stack", "--fit", "width"], "--stack and --fit can't be used together"),
+            (&["--stack", "--keep-aspect", "false"], "--stack and --keep-aspect false can't be used together"),
+            (&["--cell-colors", "average"], "--cell-colors only applies to --quadrant"),
+            (&["--cell-colors", "average", "--mode", "bg-only"], "--cell-colors only applies to --quadrant"),
+            (&["--checker-size", "2"], "--checker-size only applies to --checker"),
+            (&["--checker-shades", "0,255"], "--checker-shades only applies to --checker"),
+            (&["--sgr", "colon"], "--sgr only applies to 24-bit colour: --true-colour, --palette or --max-colors"),
+        ];
+        for &(argv, message) in conflicts {
+            let mut argv = argv.to_vec();
+            argv.push("image.png");
+            assert_eq!(validate(&args(&argv)), Err(message.to_string()), "{:?}", argv);
+        }
+    }
+
+    #[test]
+    fn validate_accepts_compatible_options() {
+        assert_eq!(validate(&args(&["--fast", "--width", "10", "image.png"])), Ok(()));
+        assert_eq!(validate(&args(&["--stack", "--keep-aspect", "true", "a.png", "b.png"])), Ok(()));
+        assert_eq!(validate(&args(&["--quadrant", "--cell-colors", "median-cut", "image.png"])), Ok(()));
+        assert_eq!(validate(&args(&["--mode", "quadrant", "--cell-colors", "average", "image.png"])), Ok(()));
+        assert_eq!(validate(&args(&["--checker", "--checker-size", "2", "image.png"])), Ok(()));
+        assert_eq!(validate(&args(&["--max-colors", "8", "--sgr", "colon", "image.png"])), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_options_out_of_place() {
+        assert_eq!(validate(&args(&["--shuffle", "image.png"])), Err("--shuffle only applies to --list".to_string()));
+        assert_eq!(validate(&args(&["--gap", "1", "image.png"])), Err("--gap only applies to --stack".to_string()));
+        assert_eq!(
+            validate(&args(&["--fit", "diagonal", "image.png"])),
+            Err("Unknown --fit side: diagonal (expected width or height)".to_string())
+        );
+    }
+
+    #[test]
+    fn slideshows_stop_when_nothing_is_drawn() {
+        assert_eq!(another_pass(true, 1, 1), Some(false));
+        assert_eq!(another_pass(true, 1, 3), Some(true));
+        assert_eq!(another_pass(true, 3, 3), Some(false));
+        assert_eq!(another_pass(true, 5, 0), Some(true));
+        // The same failure whether it's the only pass, one of several or one of forever.
+        assert_eq!(another_pass(false, 1, 1), None);
+        assert_eq!(another_pass(false, 1, 3), None);
+        assert_eq!(another_pass(false, 1, 0), None);
+    }
+
+    #[test]
+    fn validate_rejects_durations_out_of_range() {
+        for deadline in &["0", "-1", "NaN"] {
+            assert_eq!(
+                validate(&args(&["--deadline", deadline, "image.png"])),
+                Err("--deadline must be a positive number of seconds".to_string())
+            );
+        }
+        for deadline in &["1e20", "inf"] {
+            assert_eq!(
+                validate(&args(&["--deadline", deadline, "image.png"])),
+                Err(format!("--deadline is too long: {} seconds", deadline.parse::<f64>().unwrap()))
+            );
+        }
+        assert_eq!(validate(&args(&["--deadline", "0.5", "image.png"])), Ok(()));
+
+        for fps in &["0", "-1", "inf"] {
+            assert_eq!(validate(&args(&["--fps", fps, "image.png"])), Err("--fps must be a positive number".to_string()));
+        }
+        assert_eq!(validate(&args(&["--fps", "1e-20", "image.png"])), Err("--fps is too low: 0.00000000000000000001".to_string()));
+        assert_eq!(validate(&args(&["--fps", "0.5", "image.png"])), Ok(()));
+    }
+
+    #[test]
+    fn fit_to_size_keeps_a_sliver_in_a_tiny_terminal() {
+        // One row by three columns, with images far wider or taller than that.
+        assert_eq!(fit_to_size(1000, 1, 3, 1, None, None, 2), (3, 2));
+        assert_eq!(fit_to_size(1, 1000, 3, 1, None, None, 2), (1, 2));
+        assert_eq!(fit_to_size(1000, 1, 3, 1, None, None, 1), (3, 1));
+        assert_eq!(fit_to_size(1, 1000, 3, 1, None, None, 1), (1, 1));
+    }
+
+    #[test]
+    fn fit_to_size_keeps_the_aspect_in_any_mode() {
+        // A square image is twice as many columns as rows, however many pixels a row holds.
+        assert_eq!(fit_to_size(100, 100, 80, 24, None, None, 2), (48, 48));
+        assert_eq!(fit_to_size(100, 100, 80, 24, None, None, 1), (48, 24));
+        assert_eq!(fit_to_size(100, 100, 80, 24, Some(20), None, 2), (20, 20));
+        assert_eq!(fit_to_size(100, 100, 80, 24, Some(20), None, 1), (20, 10));
+    }
+
+    #[test]
+    fn determine_size_keeps_the_aspect_in_any_mode() {
+        // 100x50 pixels is 100 columns by 25 rows.
+        let width = args(&["--width", "40", "image.png"]);
+        assert_eq!(determine_size(&width, None, 2, 100, 50), (40, 20));
+        assert_eq!(determine_size(&width, None, 1, 100, 50), (40, 10));
+
+        let height = args(&["--height", "10", "image.png"]);
+        assert_eq!(determine_size(&height, None, 2, 100, 50), (40, 20));
+        assert_eq!(determine_size(&height, None, 1, 100, 50), (40, 10));
+
+        let both = args(&["--width", "80", "--height", "10", "image.png"]);
+        assert_eq!(determine_size(&both, Some(true), 2, 100, 50), (40, 20));
+        assert_eq!(determine_size(&both, Some(true), 1, 100, 50), (40, 10));
+        assert_eq!(determine_size(&both, Some(false), 2, 100, 50), (80, 20));
+
+        let stretched = args(&["--width", "40", "--pixel-aspect", "2", "image.png"]);
+        assert_eq!(determine_size(&stretched, None, 2, 100, 50), (40, 10));
+        assert_eq!(determine_size(&stretched, None, 1, 100, 50), (40, 5));
+    }
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+    const WHITE: [u8; 3] = [255, 255, 255];
+
+    /// A 2x2 uncompressed 24-bit TGA with the pixels given in file order.
+    fn tga(descriptor: u8, pixels: [[u8; 3]; 4]) -> Vec<u8> {
+        let mut data = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 24, descriptor];
+        for rgb in pixels.iter() {
+            data.extend_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+        }
+        data
+    }
+
+    /// A 2x2 24-bit BMP with the rows given in file order, stored bottom-up unless `top_down`.
+    fn bmp(top_down: bool, rows: [[[u8; 3]; 2]; 2]) -> Vec<u8> {
+        let height: i32 = if top_down { -2 } else { 2 };
+        let mut data = b"BM".to_vec();
+        data.extend_from_slice(&70u32.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&2i32.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&24u16.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        for row in rows.iter() {
+            for rgb in row.iter() {
+                data.extend_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+            }
+            // Rows are padded to a multiple of four bytes.
+            data.extend_from_slice(&[0, 0]);
+        }
+        data
+    }
+
+    fn pixels(img: &DynamicImage) -> Vec<[u8; 3]> {
+        img.to_rgb().pixels().map(|p| p.0).collect()
+    }
+
+    #[test]
+    fn tga_origins() {
+        let expected = vec![RED, GREEN, BLUE, WHITE];
+        let origins = [
+            (0x00, [BLUE, WHITE, RED, GREEN]),
+            (0x10, [WHITE, BLUE, GREEN, RED]),
+            (0x20, [RED, GREEN, BLUE, WHITE]),
+            (0x30, [GREEN, RED, WHITE, BLUE]),
+        ];
+        for &(descriptor, stored) in origins.iter() {
+            let img = get_tga(&tga(descriptor, stored)).unwrap();
+            assert_eq!(pixels(&img), expected, "descriptor {:#x}", descriptor);
+        }
+    }
+
+    #[test]
+    fn bmp_row_order() {
+        let expected = vec![RED, GREEN, BLUE, WHITE];
+        let bottom_up = image::load_from_memory(&bmp(false, [[BLUE, WHITE], [RED, GREEN]])).unwrap();
+        assert_eq!(pixels(&bottom_up), expected);
+        let top_down = image::load_from_memory(&bmp(true, [[RED, GREEN], [BLUE, WHITE]])).unwrap();
+        assert_eq!(pixels(&top_down), expected);
     }
 }
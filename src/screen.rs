@@ -0,0 +1,82 @@
+//! The terminal's alternate screen, for `--alt-screen`, and waiting for a key for `--wait`.
+//! The normal screen is put back however termpix exits, including on Ctrl-C.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Switches to the alternate screen and moves the cursor to its top left.
+const ENTER: &[u8] = b"\x1b[?1049h\x1b[H";
+const LEAVE: &[u8] = b"\x1b[?1049l";
+
+/// The descriptor the alternate screen was entered on, or -1 if it hasn't been.
+static SCREEN_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Switches the terminal on `fd` to the alternate screen, unless that's already been done.
+pub fn enter(fd: i32) {
+    if SCREEN_FD.compare_exchange(-1, fd, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return;
+    }
+    // Written straight to the descriptor, like `leave`, so that it can't be held up in a
+    // buffer and arrive out of order.
+    write_fd(fd, ENTER);
+    unsafe {
+        libc::atexit(leave_at_exit);
+        libc::signal(libc::SIGINT, leave_on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, leave_on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+/// Goes back to the normal screen, if `enter` switched away from it. Only does what's safe
+/// in a signal handler.
+fn leave() {
+    let fd = SCREEN_FD.swap(-1, Ordering::SeqCst);
+    if fd >= 0 {
+        write_fd(fd, LEAVE);
+    }
+}
+
+extern "C" fn leave_at_exit() {
+    leave();
+}
+
+/// Puts the normal screen back, then dies of `signal` as if nothing had caught it.
+extern "C" fn leave_on_signal(signal: libc::c_int) {
+    leave();
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+fn write_fd(fd: i32, bytes: &[u8]) {
+    // Nothing useful can be done if the terminal has gone away.
+    unsafe {
+        libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len());
+    }
+}
+
+/// Waits for a single key press on the controlling terminal, if there is one. Ctrl-C
+/// counts as a key, so that it ends the wait the same way.
+pub fn wait_for_key() -> io::Result<()> {
+    // Stdin may be the image, so read the terminal itself.
+    let mut tty = File::open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut raw = saved;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let read = tty.read(&mut [0]);
+    unsafe {
+        libc::tcsetattr(fd, libc::TCSANOW, &saved);
+    }
+    read.map(|_| ())
+}
@@ -0,0 +1,50 @@
+//! SVG rasterization, kept behind one function so that changes in the resvg and usvg APIs
+//! between versions only need handling here.
+
+use image::RgbaImage;
+use usvg::{FitTo, Options, Tree};
+
+/// Parses and renders an SVG so that its longer side is `target_px` pixels. With
+/// `max_pixels`, refuses to render SVGs that would come out bigger than that.
+pub fn rasterize(data: &[u8], target_px: u32, max_pixels: Option<u64>) -> Result<RgbaImage, String> {
+    let tree = Tree::from_data(data, &Options::default())
+        .map_err(|e| format!("Failed to load svg: {}", e))?;
+    let svg_size = tree.svg_node().size.to_screen_size();
+    let fit_to = if svg_size.width() >= svg_size.height() {
+        FitTo::Width(target_px)
+    } else {
+        FitTo::Height(target_px)
+    };
+    if let (Some(max_pixels), Some(raster_size)) = (max_pixels, fit_to.fit_to(svg_size)) {
+        let pixels = raster_size.width() as u64 * raster_size.height() as u64;
+        if pixels > max_pixels {
+            return Err(format!(
+                "SVG would rasterize to {}x{} pixels, more than the limit of {}",
+                raster_size.width(), raster_size.height(), max_pixels));
+        }
+    }
+
+    let rendered = resvg::render(&tree, fit_to, None)
+        .ok_or_else(|| "resvg produced no image".to_string())?;
+    // resvg un-premultiplies the rendered surface itself, so this is straight alpha.
+    RgbaImage::from_raw(rendered.width(), rendered.height(), rendered.data().to_vec())
+        .ok_or_else(|| "resvg produced an image of the wrong size".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_transparent_shapes_keep_their_colour() {
+        let svg = br##"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10">
+            <rect width="10" height="10" fill="#ff0000" fill-opacity="0.5"/>
+        </svg>"##;
+        let img = rasterize(svg, 10, None).unwrap();
+        let centre = img[(5, 5)];
+        // Premultiplied, the red would have halved along with the alpha.
+        assert!(centre[0] >= 250, "{:?}", centre);
+        assert_eq!((centre[1], centre[2]), (0, 0));
+        assert!((127..=129).contains(&centre[3]), "{:?}", centre);
+    }
+}